@@ -1,23 +1,43 @@
-use std::net::IpAddr;
+use std::{fs, io, net::IpAddr, path::Path, sync::Arc};
 
 use aes::{
-    cipher::{BlockDecryptMut, KeyIvInit},
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit},
     Aes128, Block,
 };
-use anyhow::Result;
-use cbc::Decryptor;
-use rsa::{pkcs1::DecodeRsaPrivateKey, PaddingScheme, RsaPrivateKey};
+use cbc::{Decryptor, Encryptor};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::DecodePrivateKey,
+    rand_core::{OsRng, RngCore},
+    PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey,
+};
+
+use crate::error::{Error, Result};
 
 lazy_static::lazy_static! {
     pub static ref KEY: RsaPrivateKey = RsaPrivateKey::from_pkcs1_pem(include_str!("rtsp.key")).unwrap();
 }
 
+// pkcs#1 (BEGIN RSA PRIVATE KEY) or pkcs#8 (BEGIN PRIVATE KEY) pem
+pub fn load_key(path: &Path) -> Result<RsaPrivateKey> {
+    let pem = fs::read_to_string(path).map_err(|err| io::Error::new(err.kind(), format!("Can't read private key {}: {err}", path.display())))?;
+
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+        .map_err(|_| Error::Crypto(format!("Invalid private key {}, expected pkcs#1 or pkcs#8 pem", path.display())))
+}
+
+pub fn generate_key(bits: usize) -> Result<RsaPrivateKey> {
+    Ok(RsaPrivateKey::new(&mut OsRng, bits)?)
+}
+
 pub struct AppleChallenge {
+    key: Arc<RsaPrivateKey>,
     ip_mac: Vec<u8>,
 }
 
 impl AppleChallenge {
-    pub fn new(ip_addr: IpAddr, mac_address: &[u8]) -> Self {
+    pub fn new(key: Arc<RsaPrivateKey>, ip_addr: IpAddr, mac_address: &[u8]) -> Self {
         let mut ip_mac = Vec::with_capacity(14);
 
         match ip_addr {
@@ -26,14 +46,14 @@ impl AppleChallenge {
         }
         ip_mac.extend_from_slice(mac_address);
 
-        Self { ip_mac }
+        Self { key, ip_mac }
     }
 
     pub fn response(&self, challenge: &str) -> Result<String> {
-        let mut challenge = base64::decode(challenge).unwrap();
+        let mut challenge = base64::decode(challenge)?;
         challenge.extend_from_slice(&self.ip_mac);
 
-        let response = KEY.sign(PaddingScheme::new_pkcs1v15_sign_raw(), &challenge)?;
+        let response = self.key.sign(PaddingScheme::new_pkcs1v15_sign_raw(), &challenge)?;
 
         Ok(base64::encode(response).replace('=', ""))
     }
@@ -41,14 +61,48 @@ impl AppleChallenge {
 
 pub struct RsaAesCipher {
     cipher: Decryptor<Aes128>,
+    encryptor: Encryptor<Aes128>,
 }
 
 impl RsaAesCipher {
-    pub fn new(rsaaeskey: &[u8], aesiv: &[u8]) -> Result<Self> {
-        let aeskey = KEY.decrypt(PaddingScheme::new_oaep::<sha1::Sha1>(), rsaaeskey)?;
-        let cipher = Decryptor::<Aes128>::new_from_slices(&aeskey, aesiv).unwrap();
+    pub fn new(key: &RsaPrivateKey, rsaaeskey: &[u8], aesiv: &[u8]) -> Result<Self> {
+        let aeskey = key.decrypt(PaddingScheme::new_oaep::<sha1::Sha1>(), rsaaeskey)?;
+
+        Self::from_key(&aeskey, aesiv)
+    }
 
-        Ok(Self { cipher })
+    // random key for sending to a receiver with given public key, returns cipher, rsaaeskey and aesiv
+    pub fn generate(key: &RsaPublicKey) -> Result<(Self, Vec<u8>, Vec<u8>)> {
+        let mut aeskey = [0; 16];
+        let mut aesiv = [0; 16];
+        OsRng.fill_bytes(&mut aeskey);
+        OsRng.fill_bytes(&mut aesiv);
+
+        let rsaaeskey = key.encrypt(&mut OsRng, PaddingScheme::new_oaep::<sha1::Sha1>(), &aeskey)?;
+
+        Ok((Self::from_key(&aeskey, &aesiv)?, rsaaeskey, aesiv.to_vec()))
+    }
+
+    fn from_key(aeskey: &[u8], aesiv: &[u8]) -> Result<Self> {
+        let invalid = |_| Error::Crypto("Invalid aes key or iv length".into());
+
+        Ok(Self {
+            cipher: Decryptor::<Aes128>::new_from_slices(aeskey, aesiv).map_err(invalid)?,
+            encryptor: Encryptor::<Aes128>::new_from_slices(aeskey, aesiv).map_err(invalid)?,
+        })
+    }
+
+    // every packet starts with the same iv, trailing partial block is left in clear
+    pub fn encrypt(&self, raw: &[u8]) -> Vec<u8> {
+        let mut cipher = self.encryptor.clone();
+
+        let mut encrypted = raw.to_vec();
+        encrypted.chunks_exact_mut(16).for_each(|x| {
+            let block = Block::from_mut_slice(x);
+            cipher.encrypt_block_mut(block);
+        });
+
+        encrypted
     }
 
     pub fn decrypt(&self, raw: &[u8]) -> Result<Vec<u8>> {
@@ -67,15 +121,39 @@ impl RsaAesCipher {
 #[cfg(test)]
 mod test {
     use super::*;
+    use anyhow::Result;
 
+    use rsa::{
+        pkcs1::{EncodeRsaPrivateKey, LineEnding},
+        pkcs8::EncodePrivateKey,
+    };
     use std::net::{IpAddr, Ipv4Addr};
 
+    #[tokio::test]
+    async fn load_key_test() -> Result<()> {
+        let key = generate_key(512)?;
+        let path = std::env::temp_dir().join(format!("ras_key_test_{}", std::process::id()));
+
+        fs::write(&path, key.to_pkcs8_pem(LineEnding::LF)?.as_bytes())?;
+        assert_eq!(load_key(&path)?, key);
+
+        fs::write(&path, key.to_pkcs1_pem(LineEnding::LF)?.as_bytes())?;
+        assert_eq!(load_key(&path)?, key);
+
+        fs::write(&path, "invalid")?;
+        assert!(load_key(&path).is_err());
+
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn apple_challenge_test() -> Result<()> {
         let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
         let mac_addr = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
 
-        let challenge = AppleChallenge::new(addr, &mac_addr);
+        let challenge = AppleChallenge::new(Arc::new(KEY.clone()), addr, &mac_addr);
         let response = challenge.response("test")?;
 
         assert_eq!(response, "O5TD24VQqAKIdTjPfoZzAJIrJo0Vc3gXzVAy18cWSLGN9ckUjjSWs5YCPkSmN3ExPCq2FTHtCYMW03p27K5zav97hETnJ7yLznE7cVc1RztWk0msX4MmSoN84Ei9hKDAALq/e68d6OWU+0sSX0cYcRLegkNLiCt2fNT9DnLV3PPNfBOh6bZ+PKIlqeTdAdzm73t6Lz5CBNbM7E7M/faE03XJiQHIjRylKoXRDRLwImuz8l8rWxjBjWhmcKoBbjmk1X1ohSeZWkx0ie9ySQJYyTk2PlrPFTTdA2DFrGNEIHvxPbQ94Sr5oF5lUjNaXMj2dLidRu8sQWWrhUqCkGd3JQ");
@@ -83,6 +161,20 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn generate_cipher_test() -> Result<()> {
+        let (sender, rsaaeskey, aesiv) = RsaAesCipher::generate(&KEY.to_public_key())?;
+        let receiver = RsaAesCipher::new(&KEY, &rsaaeskey, &aesiv)?;
+
+        let raw = (0..40).collect::<Vec<u8>>();
+        let encrypted = sender.encrypt(&raw);
+        assert_ne!(encrypted[..32], raw[..32]);
+        assert_eq!(encrypted[32..], raw[32..]);
+        assert_eq!(receiver.decrypt(&encrypted)?, raw);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn cipher_test() -> Result<()> {
         let key = vec![
@@ -98,7 +190,7 @@ mod test {
         ];
         let iv = vec![185, 103, 26, 130, 51, 239, 107, 111, 155, 57, 8, 107, 138, 170, 168, 207];
 
-        let cipher = RsaAesCipher::new(&key, &iv)?;
+        let cipher = RsaAesCipher::new(&KEY, &key, &iv)?;
 
         let raw = vec![
             155, 34, 3, 99, 252, 176, 190, 92, 160, 127, 189, 240, 217, 146, 246, 27, 183, 181, 224, 15, 151, 211, 28, 90, 6, 242, 154, 94, 155, 184,
@@ -123,6 +215,7 @@ mod test {
         ];
 
         let decrypted = cipher.decrypt(&raw)?;
+        assert_eq!(cipher.encrypt(&decrypted), raw);
 
         assert_eq!(
             decrypted,
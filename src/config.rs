@@ -0,0 +1,652 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use mac_address::{get_mac_address, MacAddress};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    cipher,
+    hook::Hooks,
+    ports::PortRange,
+    receiver::{Receiver, ReceiverBuilder},
+    sessions::SessionPolicy,
+    sink::{self, AudioSink, MixerAudioSink, MulticastInterface, OutputAudioSink, RtpAudioSink, TcpAudioSink, VolumeCurve},
+};
+
+// receiver configuration loaded from toml, every field is optional
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub name: String,
+    pub port: u16,
+    // addresses to listen on, both 0.0.0.0 and :: if empty
+    pub bind: Vec<IpAddr>,
+    pub session_policy: SessionPolicy,
+    // rtp, control and timing ports, i.e. "6000-6011" for four sessions. tcp data connection also uses it
+    pub udp_port_range: Option<PortRange>,
+    // accept rtp over tcp besides udp
+    pub tcp_transport: bool,
+    // pem file, embedded airport express key is used if not set
+    pub private_key: Option<PathBuf>,
+    // announced and signed in Apple-Challenge, host's one if not set
+    pub mac_address: Option<MacAddress>,
+    // Audio-Latency reported to senders, in samples
+    pub latency: Option<u32>,
+    // seconds without rtsp keep-alive or rtp traffic before a session is torn down
+    pub idle_timeout: u64,
+    // added to or overriding the default txt record
+    pub txt: BTreeMap<String, String>,
+    pub sink: SinkConfig,
+    pub volume: VolumeCurve,
+    pub hooks: HookConfig,
+    // several receivers in one process, each one replaces the receiver configured above
+    #[serde(rename = "receiver")]
+    pub receivers: Vec<ReceiverConfig>,
+    // fields set by set_name, set_port and set_sink, i.e. from command line or environment
+    #[serde(skip)]
+    overridden: Vec<&'static str>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: "ras".into(),
+            port: 7000,
+            bind: Vec::new(),
+            session_policy: SessionPolicy::default(),
+            udp_port_range: None,
+            tcp_transport: false,
+            private_key: None,
+            mac_address: None,
+            latency: None,
+            idle_timeout: 120,
+            txt: BTreeMap::new(),
+            sink: SinkConfig::default(),
+            volume: VolumeCurve::default(),
+            hooks: HookConfig::default(),
+            receivers: Vec::new(),
+            overridden: Vec::new(),
+        }
+    }
+}
+
+// `[[receiver]]` table, fields not set here are taken from the top level
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiverConfig {
+    pub name: String,
+    pub port: u16,
+    pub session_policy: Option<SessionPolicy>,
+    // derived from the top level or host's one and the name if not set, senders tell receivers apart by it
+    pub mac_address: Option<MacAddress>,
+    // added to or overriding the top level txt
+    #[serde(default)]
+    pub txt: BTreeMap<String, String>,
+    pub sink: Option<SinkConfig>,
+    pub volume: Option<VolumeCurve>,
+}
+
+// `type` selects the sink, other sinks' sections are ignored
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    // sum concurrent sessions into one stream with soft clipping, instead of one stream per session
+    pub mixer: bool,
+    pub rtp: Option<NetworkSinkConfig>,
+    pub tcp: Option<NetworkSinkConfig>,
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        Self {
+            kind: "rodio".into(),
+            mixer: false,
+            rtp: None,
+            tcp: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSinkConfig {
+    pub address: SocketAddr,
+    // rtp multicast only, hops to cross, 1 if not set
+    pub ttl: Option<u32>,
+    // rtp multicast only, address of the interface to send on for ipv4, its index for ipv6
+    pub interface: Option<MulticastInterface>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookConfig {
+    pub session_start: Option<String>,
+    pub session_end: Option<String>,
+    pub track_change: Option<String>,
+    // in seconds
+    pub timeout: u64,
+    pub wait: bool,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        let hooks = Hooks::default();
+
+        Self {
+            session_start: hooks.session_start,
+            session_end: hooks.session_end,
+            track_change: hooks.track_change,
+            timeout: hooks.timeout.as_secs(),
+            wait: hooks.wait,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("Can't read config file {}", path.display()))?;
+
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let config = toml::from_str::<Self>(content)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+        self.overridden.push("name");
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+        self.overridden.push("port");
+    }
+
+    // sink in `--audio-sink` form, i.e. `rtp:239.255.0.1:5004` also sets address of the sink section
+    pub fn set_sink(&mut self, sink: &str) -> Result<()> {
+        self.overridden.push("sink");
+        let (kind, address) = match sink.split_once(':') {
+            Some((kind, address)) => (kind, Some(address)),
+            None => (sink, None),
+        };
+
+        if let Some(address) = address {
+            let address = address.parse().with_context(|| format!("Invalid sink address {address:?}"))?;
+
+            match kind {
+                // multicast options of the config file are kept
+                "rtp" => match &mut self.sink.rtp {
+                    Some(rtp) => rtp.address = address,
+                    None => {
+                        self.sink.rtp = Some(NetworkSinkConfig {
+                            address,
+                            ttl: None,
+                            interface: None,
+                        })
+                    }
+                },
+                "tcp" => {
+                    self.sink.tcp = Some(NetworkSinkConfig {
+                        address,
+                        ttl: None,
+                        interface: None,
+                    })
+                }
+                _ => return Err(anyhow!("Sink {kind:?} doesn't take an address")),
+            }
+        }
+        self.sink.kind = kind.into();
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(anyhow!("name: must not be empty"));
+        }
+        if self.name.contains('@') {
+            return Err(anyhow!("name: must not contain '@'"));
+        }
+
+        for (i, addr) in self.bind.iter().enumerate() {
+            if self.bind[..i].contains(addr) {
+                return Err(anyhow!("bind: duplicate address {addr}"));
+            }
+        }
+
+        if let Some(range) = self.udp_port_range {
+            if range.end - range.start < 2 {
+                return Err(anyhow!("udp_port_range: needs at least 3 ports"));
+            }
+        }
+
+        for key in self.txt.keys() {
+            if key.is_empty() || key.contains('=') {
+                return Err(anyhow!("txt: invalid key {key:?}"));
+            }
+        }
+
+        match self.sink.kind.as_str() {
+            "dummy" | "rodio" => {}
+            "rtp" if self.sink.rtp.is_none() => return Err(anyhow!("sink.rtp.address: required for rtp sink")),
+            "tcp" if self.sink.tcp.is_none() => return Err(anyhow!("sink.tcp.address: required for tcp sink")),
+            "rtp" | "tcp" => {}
+            kind => return Err(anyhow!("sink.type: unknown sink {kind:?}, expected dummy, rodio, rtp or tcp")),
+        }
+        if let Some(rtp) = &self.sink.rtp {
+            if rtp.ttl.is_some_and(|x| x == 0 || x > 255) {
+                return Err(anyhow!("sink.rtp.ttl: must be 1 ~ 255"));
+            }
+            match (rtp.address, rtp.interface) {
+                (SocketAddr::V4(_), Some(MulticastInterface::Index(_))) => {
+                    return Err(anyhow!("sink.rtp.interface: must be an interface address for ipv4"))
+                }
+                (SocketAddr::V6(_), Some(MulticastInterface::Address(_))) => {
+                    return Err(anyhow!("sink.rtp.interface: must be an interface index for ipv6"))
+                }
+                _ => {}
+            }
+        }
+        if self.sink.tcp.as_ref().is_some_and(|x| x.ttl.is_some() || x.interface.is_some()) {
+            return Err(anyhow!("sink.tcp: ttl and interface are only for rtp sink"));
+        }
+
+        if !self.volume.max_db.is_finite() || !self.volume.min_db.is_finite() || self.volume.min_db >= self.volume.max_db {
+            return Err(anyhow!("volume: min_db must be less than max_db"));
+        }
+
+        if self.idle_timeout == 0 {
+            return Err(anyhow!("idle_timeout: must be positive"));
+        }
+
+        if self.hooks.timeout == 0 {
+            return Err(anyhow!("hooks.timeout: must be positive"));
+        }
+
+        // it can't tell which receiver it's meant for
+        if let Some(field) = self.overridden.first().filter(|_| !self.receivers.is_empty()) {
+            return Err(anyhow!(
+                "{field}: can't be set from command line or environment along with [[receiver]] tables, set it in each receiver"
+            ));
+        }
+
+        let receivers = self.receivers();
+        for (i, receiver) in self.receivers.iter().enumerate() {
+            if self.receivers[..i].iter().any(|x| x.name == receiver.name) {
+                return Err(anyhow!("receiver[{i}].name: duplicate name {:?}", receiver.name));
+            }
+            if receiver.port != 0 && self.receivers[..i].iter().any(|x| x.port == receiver.port) {
+                return Err(anyhow!("receiver[{i}].port: duplicate port {}", receiver.port));
+            }
+            if let Some(mac_address) = receiver.mac_address {
+                if self.receivers[..i].iter().any(|x| x.mac_address == Some(mac_address)) {
+                    return Err(anyhow!("receiver[{i}].mac_address: duplicate address {mac_address}"));
+                }
+            }
+
+            receivers[i].validate().map_err(|err| anyhow!("receiver[{i}].{err}"))?;
+        }
+
+        Ok(())
+    }
+
+    // configuration of every receiver to run, the top level one unless `[[receiver]]` tables are given
+    pub fn receivers(&self) -> Vec<Config> {
+        if self.receivers.is_empty() {
+            return vec![self.clone()];
+        }
+        let base_mac_address = self.mac_address.or_else(|| get_mac_address().ok().flatten());
+
+        self.receivers
+            .iter()
+            .map(|receiver| {
+                let mut config = Config {
+                    name: receiver.name.clone(),
+                    port: receiver.port,
+                    receivers: Vec::new(),
+                    ..self.clone()
+                };
+                if let Some(session_policy) = receiver.session_policy {
+                    config.session_policy = session_policy;
+                }
+                config.mac_address = receiver
+                    .mac_address
+                    .or_else(|| base_mac_address.map(|base| derive_mac_address(base, &receiver.name)));
+                config.txt.extend(receiver.txt.clone());
+                if let Some(sink) = &receiver.sink {
+                    config.sink = sink.clone();
+                }
+                if let Some(volume) = receiver.volume {
+                    config.volume = volume;
+                }
+
+                config
+            })
+            .collect()
+    }
+
+    pub fn create_sink(&self) -> Result<Arc<dyn AudioSink>> {
+        let sink: Arc<dyn AudioSink> = match (self.sink.kind.as_str(), &self.sink.rtp, &self.sink.tcp) {
+            ("rtp", Some(rtp), _) => Arc::new(RtpAudioSink::with_multicast(rtp.address, rtp.ttl, rtp.interface)?),
+            ("tcp", _, Some(tcp)) => Arc::new(TcpAudioSink::new(tcp.address)?),
+            (kind, _, _) => sink::open(kind)?,
+        };
+
+        // output thread paces every session, mixer sums them on its ticks
+        let sink: Arc<dyn AudioSink> = if self.sink.mixer { Arc::new(MixerAudioSink::new(sink)) } else { sink };
+
+        Ok(Arc::new(OutputAudioSink::new(sink)?))
+    }
+
+    pub fn hooks(&self) -> Hooks {
+        Hooks {
+            session_start: self.hooks.session_start.clone(),
+            session_end: self.hooks.session_end.clone(),
+            track_change: self.hooks.track_change.clone(),
+            timeout: Duration::from_secs(self.hooks.timeout),
+            wait: self.hooks.wait,
+        }
+    }
+
+    // receiver builder with everything but the sink configured
+    pub fn builder(&self) -> Result<ReceiverBuilder> {
+        let mut builder = Receiver::builder()
+            .name(&self.name)
+            .port(self.port)
+            .session_policy(self.session_policy)
+            .tcp_transport(self.tcp_transport)
+            .volume_curve(self.volume)
+            .idle_timeout(Duration::from_secs(self.idle_timeout))
+            .hooks(self.hooks());
+
+        for addr in &self.bind {
+            builder = builder.bind(*addr);
+        }
+        if let Some(udp_port_range) = self.udp_port_range {
+            builder = builder.udp_port_range(udp_port_range);
+        }
+        if let Some(private_key) = &self.private_key {
+            builder = builder.key(cipher::load_key(private_key)?);
+        }
+        if let Some(mac_address) = self.mac_address {
+            builder = builder.mac_address(mac_address);
+        }
+        if let Some(latency) = self.latency {
+            builder = builder.latency(latency);
+        }
+        for (key, value) in &self.txt {
+            builder = builder.txt(key, value);
+        }
+
+        Ok(builder)
+    }
+}
+
+// stable across restarts, so senders keep recognizing the receiver
+fn derive_mac_address(base: MacAddress, name: &str) -> MacAddress {
+    let digest = Sha1::new().chain_update(base.bytes()).chain_update(name.as_bytes()).finalize();
+
+    let mut bytes = [0; 6];
+    bytes.copy_from_slice(&digest[..6]);
+    // locally administered unicast, so it can't collide with a real interface
+    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+
+    MacAddress::new(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_config() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            name = "Kitchen"
+            bind = ["::"]
+            session_policy = "reject"
+            udp_port_range = "6000-6011"
+            tcp_transport = true
+            latency = 11025
+            idle_timeout = 30
+
+            [txt]
+            am = "AirPort4,107"
+
+            [sink]
+            type = "rtp"
+            mixer = true
+
+            [sink.rtp]
+            address = "239.255.0.1:5004"
+            ttl = 4
+            interface = "192.168.0.2"
+
+            [volume]
+            max_db = -6.0
+
+            [hooks]
+            session_start = "amp on"
+            timeout = 10
+            "#,
+        )?;
+
+        assert_eq!(config.name, "Kitchen");
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.bind, vec![IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED)]);
+        assert_eq!(config.session_policy, SessionPolicy::Reject);
+        assert_eq!(config.udp_port_range, Some(PortRange { start: 6000, end: 6011 }));
+        assert!(config.tcp_transport);
+        assert_eq!(config.latency, Some(11025));
+        assert_eq!(config.idle_timeout, 30);
+        assert_eq!(config.txt.get("am").map(|x| x.as_str()), Some("AirPort4,107"));
+        assert!(config.sink.mixer);
+        let rtp = config.sink.rtp.as_ref().unwrap();
+        assert_eq!(rtp.address, "239.255.0.1:5004".parse()?);
+        assert_eq!(rtp.ttl, Some(4));
+        assert_eq!(rtp.interface, Some(MulticastInterface::Address("192.168.0.2".parse()?)));
+        assert_eq!(config.volume.max_db, -6.0);
+        assert_eq!(config.volume.min_db, -30.0);
+        assert_eq!(config.hooks().session_start.as_deref(), Some("amp on"));
+        assert_eq!(config.hooks().timeout, Duration::from_secs(10));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_config_errors() -> Result<()> {
+        let error = |content: &str| format!("{:#}", Config::parse(content).unwrap_err());
+
+        assert!(error("prot = 7000").contains("unknown field `prot`"));
+        assert!(error("port = \"7000\"").contains("invalid type"));
+        assert!(error("udp_port_range = \"6000\"").contains("Invalid port range"));
+        assert_eq!(error("bind = [\"::1\", \"::1\"]"), "bind: duplicate address ::1");
+        assert!(error("session_policy = \"share\"").contains("unknown variant `share`"));
+        assert_eq!(error("[sink]\ntype = \"rtp\""), "sink.rtp.address: required for rtp sink");
+        assert_eq!(
+            error("[sink.rtp]\naddress = \"239.255.0.1:5004\"\nttl = 0"),
+            "sink.rtp.ttl: must be 1 ~ 255"
+        );
+        assert_eq!(
+            error("[sink.rtp]\naddress = \"[ff05::1]:5004\"\ninterface = \"192.168.0.2\""),
+            "sink.rtp.interface: must be an interface index for ipv6"
+        );
+        assert_eq!(
+            error("[sink.tcp]\naddress = \"0.0.0.0:5000\"\nttl = 4"),
+            "sink.tcp: ttl and interface are only for rtp sink"
+        );
+        assert_eq!(error("[volume]\nmin_db = 0.0"), "volume: min_db must be less than max_db");
+        assert_eq!(error("idle_timeout = 0"), "idle_timeout: must be positive");
+        assert!(error("[sink]\ntype = \"alsa\"").starts_with("sink.type: unknown sink"));
+        assert_eq!(
+            error("[[receiver]]\nname = \"a\"\nport = 7000\n[[receiver]]\nname = \"a\"\nport = 7001"),
+            "receiver[1].name: duplicate name \"a\""
+        );
+        assert_eq!(
+            error("[[receiver]]\nname = \"a\"\nport = 7000\n[[receiver]]\nname = \"b\"\nport = 7000"),
+            "receiver[1].port: duplicate port 7000"
+        );
+        assert_eq!(
+            error("[[receiver]]\nname = \"a\"\nport = 7000\nmac_address = \"02:00:00:00:00:01\"\n[[receiver]]\nname = \"b\"\nport = 7001\nmac_address = \"02:00:00:00:00:01\""),
+            "receiver[1].mac_address: duplicate address 02:00:00:00:00:01"
+        );
+        assert_eq!(
+            error("[[receiver]]\nname = \"a\"\nport = 7000\n[receiver.sink]\ntype = \"tcp\""),
+            "receiver[0].sink.tcp.address: required for tcp sink"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overrides() -> Result<()> {
+        let mut config = Config::default();
+        config.set_name("Kitchen");
+        config.set_port(7001);
+        config.set_sink("tcp:0.0.0.0:5000")?;
+        config.validate()?;
+
+        let mut config = Config::parse("[[receiver]]\nname = \"a\"\nport = 7000")?;
+        config.set_port(7001);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "port: can't be set from command line or environment along with [[receiver]] tables, set it in each receiver"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receivers() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            session_policy = "reject"
+            udp_port_range = "6000-6011"
+
+            [txt]
+            am = "AirPort4,107"
+
+            [[receiver]]
+            name = "Kitchen"
+            port = 7001
+
+            [receiver.sink]
+            type = "tcp"
+
+            [receiver.sink.tcp]
+            address = "0.0.0.0:5000"
+
+            [[receiver]]
+            name = "Office"
+            port = 7002
+            session_policy = "preempt"
+            txt = { am = "AudioAccessory1,1" }
+
+            [receiver.volume]
+            ignore_sender = true
+            "#,
+        )?;
+
+        let receivers = config.receivers();
+        assert_eq!(receivers.len(), 2);
+
+        assert_eq!((receivers[0].name.as_str(), receivers[0].port), ("Kitchen", 7001));
+        assert_eq!(receivers[0].session_policy, SessionPolicy::Reject);
+        assert_eq!(receivers[0].sink.kind, "tcp");
+        assert_eq!(receivers[0].udp_port_range, config.udp_port_range);
+        assert!(!receivers[0].volume.ignore_sender);
+
+        assert_eq!((receivers[1].name.as_str(), receivers[1].port), ("Office", 7002));
+        assert_eq!(receivers[1].session_policy, SessionPolicy::Preempt);
+        assert_eq!(receivers[1].sink.kind, "rodio");
+        assert_eq!(receivers[1].txt.get("am").map(|x| x.as_str()), Some("AudioAccessory1,1"));
+        assert!(receivers[1].volume.ignore_sender);
+
+        assert_eq!(Config::default().receivers().len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receiver_mac_address() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            mac_address = "00:11:22:33:44:55"
+
+            [[receiver]]
+            name = "Kitchen"
+            port = 7001
+
+            [[receiver]]
+            name = "Office"
+            port = 7002
+
+            [[receiver]]
+            name = "Garage"
+            port = 7003
+            mac_address = "02:00:00:00:00:01"
+            "#,
+        )?;
+
+        let instances = config
+            .receivers()
+            .iter()
+            .map(|config| Ok(config.builder()?.sink(Arc::new(sink::DummyAudioSink::default())).build()?.instance()))
+            .collect::<Result<Vec<_>>>()?;
+        let macs = instances.iter().map(|x| x.split('@').next().unwrap_or_default()).collect::<Vec<_>>();
+
+        // each receiver is a device of its own to senders
+        assert_ne!(macs[0], macs[1]);
+        assert!(!macs[..2].contains(&"001122334455"));
+        assert_eq!(macs[2], "020000000001");
+        assert_eq!(instances[0].split_once('@').map(|x| x.1), Some("Kitchen"));
+
+        // same on every start, senders would see a new device otherwise
+        assert_eq!(macs[0], "A65849428B90");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_sink() -> Result<()> {
+        let mut config = Config::default();
+
+        config.set_sink("tcp:127.0.0.1:5000")?;
+        assert_eq!(config.sink.kind, "tcp");
+        assert_eq!(config.sink.tcp.as_ref().map(|x| x.address), Some("127.0.0.1:5000".parse()?));
+        config.validate()?;
+
+        // multicast options from config file stay
+        config.sink.rtp = Some(NetworkSinkConfig {
+            address: "239.255.0.1:5004".parse()?,
+            ttl: Some(4),
+            interface: Some(MulticastInterface::Index(2)),
+        });
+        config.set_sink("rtp:[ff05::1]:5004")?;
+        assert_eq!(
+            config.sink.rtp.as_ref().map(|x| (x.address, x.ttl)),
+            Some(("[ff05::1]:5004".parse()?, Some(4)))
+        );
+        config.validate()?;
+
+        assert!(config.set_sink("rodio:foo").is_err());
+        assert!(config.set_sink("tcp:foo").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::{self, FromStr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error, Result};
+use log::debug;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::mdns;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// commands the sender accepts on its dacp service
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemoteCommand {
+    PlayPause,
+    NextItem,
+    PrevItem,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl RemoteCommand {
+    fn path(&self) -> &'static str {
+        match self {
+            RemoteCommand::PlayPause => "/ctrl-int/1/playpause",
+            RemoteCommand::NextItem => "/ctrl-int/1/nextitem",
+            RemoteCommand::PrevItem => "/ctrl-int/1/previtem",
+            RemoteCommand::VolumeUp => "/ctrl-int/1/volumeup",
+            RemoteCommand::VolumeDown => "/ctrl-int/1/volumedown",
+        }
+    }
+}
+
+impl FromStr for RemoteCommand {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "playpause" => Ok(Self::PlayPause),
+            "nextitem" => Ok(Self::NextItem),
+            "previtem" => Ok(Self::PrevItem),
+            "volumeup" => Ok(Self::VolumeUp),
+            "volumedown" => Ok(Self::VolumeDown),
+            _ => Err(anyhow!("Unknown remote command {s:?}")),
+        }
+    }
+}
+
+// controls the sender using DACP-ID and Active-Remote it sent to us
+pub struct DacpClient {
+    addr: SocketAddr,
+    active_remote: String,
+}
+
+impl DacpClient {
+    pub fn new(addr: SocketAddr, active_remote: &str) -> Self {
+        Self {
+            addr,
+            active_remote: active_remote.into(),
+        }
+    }
+
+    // sender advertises `iTunes_Ctrl_<DACP-ID>._dacp._tcp` on the same host it connected from
+    pub async fn discover(host: IpAddr, dacp_id: &str, active_remote: &str) -> Result<Self> {
+        let record = mdns::query_srv(&format!("iTunes_Ctrl_{dacp_id}._dacp._tcp.local"), host).await?;
+
+        Ok(Self::new(SocketAddr::new(host, record.port), active_remote))
+    }
+
+    pub async fn send(&self, command: RemoteCommand) -> Result<()> {
+        debug!("Sending {:?} to {}", command, self.addr);
+
+        timeout(REQUEST_TIMEOUT, self.request(command.path()))
+            .await
+            .map_err(|_| anyhow!("Dacp request timed out"))?
+    }
+
+    async fn request(&self, path: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(self.addr).await?;
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nActive-Remote: {}\r\nConnection: close\r\n\r\n",
+            path, self.addr, self.active_remote
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // we only need status line
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.windows(2).any(|x| x == b"\r\n") {
+            let length = stream.read(&mut buf).await?;
+            if length == 0 {
+                return Err(anyhow!("Dacp connection closed before response"));
+            }
+            response.extend_from_slice(&buf[..length]);
+        }
+
+        let status_line = str::from_utf8(&response)?.lines().next().unwrap_or_default();
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .ok_or_else(|| anyhow!("Invalid dacp response {status_line:?}"))?;
+
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(anyhow!("Dacp request failed: {status_line}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    async fn serve_once(listener: TcpListener, status_line: &'static str) -> Result<String> {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+            let length = stream.read(&mut buf).await?;
+            request.extend_from_slice(&buf[..length]);
+        }
+        stream.write_all(format!("{status_line}\r\nContent-Length: 0\r\n\r\n").as_bytes()).await?;
+
+        Ok(String::from_utf8(request)?)
+    }
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = DacpClient::new(listener.local_addr()?, "1986535575");
+
+        let server = tokio::spawn(serve_once(listener, "HTTP/1.1 204 No Content"));
+        client.send(RemoteCommand::NextItem).await?;
+
+        let request = server.await??;
+        assert!(request.starts_with("GET /ctrl-int/1/nextitem HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nActive-Remote: 1986535575\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_failure() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = DacpClient::new(listener.local_addr()?, "1986535575");
+
+        let server = tokio::spawn(serve_once(listener, "HTTP/1.1 403 Forbidden"));
+        assert!(client.send(RemoteCommand::PlayPause).await.is_err());
+        server.await??;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,5 @@
-use std::{mem::size_of, slice};
+use std::{mem::size_of, slice, str::FromStr, sync::Arc};
 
-use anyhow::Result;
 use symphonia::{
     core::{
         audio::RawSampleBuffer,
@@ -11,7 +10,10 @@ use symphonia::{
     default::codecs::AlacDecoder,
 };
 
-use crate::sink::AudioFormat;
+use crate::{
+    error::{Error, Result},
+    sink::AudioFormat,
+};
 
 pub trait Decoder: Send + Sync {
     fn channels(&self) -> u8;
@@ -20,6 +22,17 @@ pub trait Decoder: Send + Sync {
     fn decode(&self, raw: &[u8]) -> Result<Vec<u8>>;
 }
 
+// codec description from ANNOUNCE sdp
+pub struct CodecInfo {
+    pub name: String,
+    pub payload_type: u8,
+    pub clock_rate: u32,
+    pub encoding_parameters: String,
+    pub fmtp: Option<String>,
+}
+
+pub type DecoderFactory = Arc<dyn Fn(&CodecInfo) -> Result<Box<dyn Decoder>> + Send + Sync>;
+
 #[repr(C)]
 #[repr(packed)]
 struct MagicCookie {
@@ -45,6 +58,7 @@ pub struct AppleLoselessDecoder {
 impl AppleLoselessDecoder {
     pub fn new(fmtp: &str) -> Result<Self> {
         let magic_cookie = Self::fmtp_to_magic_cookie(fmtp)?;
+        check_format(magic_cookie.num_channels, u32::from_be_bytes(magic_cookie.sample_rate))?;
 
         Ok(Self {
             channels: magic_cookie.num_channels,
@@ -56,18 +70,22 @@ impl AppleLoselessDecoder {
     fn fmtp_to_magic_cookie(fmtp: &str) -> Result<MagicCookie> {
         // symphonia doesn't supports fmtp parsing, so here converts it to alac magic cookie manually
         let fmtp_params = fmtp.split(' ').collect::<Vec<_>>();
+        if fmtp_params.len() != 11 {
+            return Err(Error::Decode(format!("Expected 11 fmtp parameters, got {}", fmtp_params.len())));
+        }
+
         Ok(MagicCookie {
-            frame_length: fmtp_params[0].parse::<u32>()?.to_be_bytes(),
-            compatible_version: fmtp_params[1].parse()?,
-            bit_depth: fmtp_params[2].parse()?,
-            pb: fmtp_params[3].parse()?,
-            mb: fmtp_params[4].parse()?,
-            kb: fmtp_params[5].parse()?,
-            num_channels: fmtp_params[6].parse()?,
-            max_run: fmtp_params[7].parse::<u16>()?.to_be_bytes(),
-            max_frame_bytes: fmtp_params[8].parse::<u32>()?.to_be_bytes(),
-            avg_bit_rate: fmtp_params[9].parse::<u32>()?.to_be_bytes(),
-            sample_rate: fmtp_params[10].parse::<u32>()?.to_be_bytes(),
+            frame_length: parse_param::<u32>(fmtp_params[0])?.to_be_bytes(),
+            compatible_version: parse_param(fmtp_params[1])?,
+            bit_depth: parse_param(fmtp_params[2])?,
+            pb: parse_param(fmtp_params[3])?,
+            mb: parse_param(fmtp_params[4])?,
+            kb: parse_param(fmtp_params[5])?,
+            num_channels: parse_param(fmtp_params[6])?,
+            max_run: parse_param::<u16>(fmtp_params[7])?.to_be_bytes(),
+            max_frame_bytes: parse_param::<u32>(fmtp_params[8])?.to_be_bytes(),
+            avg_bit_rate: parse_param::<u32>(fmtp_params[9])?.to_be_bytes(),
+            sample_rate: parse_param::<u32>(fmtp_params[10])?.to_be_bytes(),
         })
     }
 }
@@ -87,7 +105,9 @@ impl Decoder for AppleLoselessDecoder {
 
     fn decode(&self, raw: &[u8]) -> Result<Vec<u8>> {
         let magic_cookie_data: [u8; 24] =
-            (unsafe { slice::from_raw_parts(&self.magic_cookie as *const MagicCookie as *const u8, size_of::<MagicCookie>()) }).try_into()?;
+            (unsafe { slice::from_raw_parts(&self.magic_cookie as *const MagicCookie as *const u8, size_of::<MagicCookie>()) })
+                .try_into()
+                .map_err(|_| Error::Decode("Invalid magic cookie".into()))?;
 
         let mut decoder = AlacDecoder::try_new(
             CodecParameters::new()
@@ -109,6 +129,19 @@ impl Decoder for AppleLoselessDecoder {
     }
 }
 
+fn parse_param<T: FromStr>(param: &str) -> Result<T> {
+    param.parse().map_err(|_| Error::Decode(format!("Invalid fmtp parameter {param:?}")))
+}
+
+// sinks can't open a stream without channels or rate
+fn check_format(channels: u8, rate: u32) -> Result<()> {
+    if channels == 0 || rate == 0 {
+        return Err(Error::Decode(format!("Invalid format, {} channels at {}Hz", channels, rate)));
+    }
+
+    Ok(())
+}
+
 pub struct RawPCMDecoder {
     format: AudioFormat,
     channels: u8,
@@ -117,6 +150,8 @@ pub struct RawPCMDecoder {
 
 impl RawPCMDecoder {
     pub fn new(format: AudioFormat, channels: u8, rate: u32) -> Result<Self> {
+        check_format(channels, rate)?;
+
         Ok(Self { format, channels, rate })
     }
 }
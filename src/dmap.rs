@@ -0,0 +1,123 @@
+use std::str;
+
+use anyhow::{anyhow, Result};
+
+use crate::event::Metadata;
+
+// containers nest only a level or two, deeper ones are rejected instead of recursing without bound
+const MAX_DEPTH: usize = 8;
+
+// parses metadata from SET_PARAMETER with application/x-dmap-tagged
+pub fn parse_metadata(data: &[u8]) -> Result<Metadata> {
+    parse_container(data, 0)
+}
+
+fn parse_container(data: &[u8], depth: usize) -> Result<Metadata> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Too deeply nested dmap"));
+    }
+    let mut metadata = Metadata::default();
+
+    for (tag, value) in items(data)? {
+        match tag {
+            // metadata is usually wrapped in listing item container
+            b"mlit" => {
+                let item = parse_container(value, depth + 1)?;
+
+                metadata.title = item.title.or(metadata.title);
+                metadata.artist = item.artist.or(metadata.artist);
+                metadata.album = item.album.or(metadata.album);
+                metadata.genre = item.genre.or(metadata.genre);
+            }
+            b"minm" => metadata.title = Some(str::from_utf8(value)?.into()),
+            b"asar" => metadata.artist = Some(str::from_utf8(value)?.into()),
+            b"asal" => metadata.album = Some(str::from_utf8(value)?.into()),
+            b"asgn" => metadata.genre = Some(str::from_utf8(value)?.into()),
+            _ => {}
+        }
+    }
+
+    Ok(metadata)
+}
+
+// each item is 4 byte tag, 4 byte big endian length and value
+fn items(mut data: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let mut result = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(anyhow!("Truncated dmap item header"));
+        }
+
+        let tag = &data[..4];
+        let length = u32::from_be_bytes(data[4..8].try_into()?) as usize;
+        if data.len() - 8 < length {
+            return Err(anyhow!("Truncated dmap item {:?}", String::from_utf8_lossy(tag)));
+        }
+
+        result.push((tag, &data[8..8 + length]));
+        data = &data[8 + length..];
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    fn item(tag: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut result = tag.to_vec();
+        result.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        result.extend_from_slice(value);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> Result<()> {
+        let content = [
+            item(b"mikd", &[2]),
+            item(b"minm", b"Title"),
+            item(b"asar", b"Artist"),
+            item(b"asal", b"Album"),
+            item(b"asgn", b"Genre"),
+            item(b"astm", &[0, 0, 0, 1]),
+        ]
+        .concat();
+        let data = item(b"mlit", &content);
+
+        let metadata = parse_metadata(&data)?;
+
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.genre.as_deref(), Some("Genre"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncated() -> Result<()> {
+        let data = item(b"minm", b"Title");
+
+        assert!(parse_metadata(&data[..data.len() - 1]).is_err());
+        assert!(parse_metadata(&data[..4]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested() -> Result<()> {
+        let mut data = item(b"minm", b"Title");
+        for _ in 0..MAX_DEPTH {
+            data = item(b"mlit", &data);
+        }
+        assert_eq!(parse_metadata(&data)?.title.as_deref(), Some("Title"));
+
+        assert!(parse_metadata(&item(b"mlit", &data)).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use std::io;
+
+use thiserror::Error;
+
+// errors of protocol, crypto, codec and sink layers, so callers can tell a bad packet from a broken socket
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid RTSP message: {0}")]
+    Rtsp(String),
+    #[error("Invalid SDP: {0}")]
+    Sdp(String),
+    #[error("Invalid RTP packet: {0}")]
+    Rtp(String),
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+    #[error("Can't decode audio: {0}")]
+    Decode(String),
+    #[error("Audio sink error: {0}")]
+    Sink(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    // caused by what the peer sent, as opposed to a local failure
+    pub fn is_protocol(&self) -> bool {
+        matches!(self, Error::Rtsp(_) | Error::Sdp(_) | Error::Rtp(_) | Error::Crypto(_) | Error::Decode(_))
+    }
+}
+
+impl From<rsa::errors::Error> for Error {
+    fn from(err: rsa::errors::Error) -> Self {
+        Error::Crypto(err.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Self {
+        Error::Crypto(err.to_string())
+    }
+}
+
+impl From<symphonia::core::errors::Error> for Error {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        Error::Decode(err.to_string())
+    }
+}
@@ -0,0 +1,73 @@
+use std::{fmt, net::SocketAddr};
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub session_id: u32,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventKind {
+    Connected {
+        addr: SocketAddr,
+        user_agent: Option<String>,
+        client_name: Option<String>,
+    },
+    Announced {
+        codec: String,
+    },
+    PlaybackStarted,
+    Paused,
+    Flushed,
+    // airplay volume in decibel, -30.0 ~ 0.0, -144.0 is mute
+    VolumeChanged {
+        volume: f32,
+    },
+    Metadata(Metadata),
+    Artwork {
+        content_type: String,
+        data: Vec<u8>,
+    },
+    // rtp timestamps of track start, current position and track end
+    Progress {
+        start: u32,
+        current: u32,
+        end: u32,
+    },
+    SessionEnded {
+        reason: SessionEndReason,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEndReason {
+    Teardown,
+    Disconnected,
+    Preempted,
+    // no rtsp request nor rtp packet within idle timeout, i.e. sender dropped off the network
+    Timeout,
+    // receiver is shutting down
+    Shutdown,
+    Error(String),
+}
+
+impl fmt::Display for SessionEndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionEndReason::Teardown => write!(f, "teardown"),
+            SessionEndReason::Disconnected => write!(f, "disconnected"),
+            SessionEndReason::Preempted => write!(f, "preempted"),
+            SessionEndReason::Timeout => write!(f, "timeout"),
+            SessionEndReason::Shutdown => write!(f, "shutdown"),
+            SessionEndReason::Error(_) => write!(f, "error"),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::{process::Stdio, time::Duration};
+
+use log::{debug, warn};
+use tokio::{process::Command, time::timeout};
+
+// shell commands run on session lifecycle, details are passed in RAS_* environment variables
+#[derive(Clone, Debug)]
+pub struct Hooks {
+    // after RECORD
+    pub session_start: Option<String>,
+    // after TEARDOWN or disconnect of a session which started playing
+    pub session_end: Option<String>,
+    // when sender sends new track metadata
+    pub track_change: Option<String>,
+    // commands running longer than this are killed
+    pub timeout: Duration,
+    // whether session waits for commands to finish, e.g. for amplifier to power up before playback
+    pub wait: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            session_start: None,
+            session_end: None,
+            track_change: None,
+            timeout: Duration::from_secs(5),
+            wait: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum HookEvent {
+    SessionStart,
+    SessionEnd,
+    TrackChange,
+}
+
+impl Hooks {
+    pub(crate) async fn run(&self, event: HookEvent, env: Vec<(&'static str, String)>) {
+        let command = match event {
+            HookEvent::SessionStart => &self.session_start,
+            HookEvent::SessionEnd => &self.session_end,
+            HookEvent::TrackChange => &self.track_change,
+        };
+        let command = if let Some(command) = command { command } else { return };
+
+        debug!("Running {:?} hook {:?}", event, command);
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(env)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                warn!("Failed to run {:?} hook: {:?}", event, err);
+                return;
+            }
+        };
+
+        let hook_timeout = self.timeout;
+        let wait = async move {
+            match timeout(hook_timeout, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => warn!("{:?} hook exited with {}", event, status),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => warn!("Failed to wait {:?} hook: {:?}", event, err),
+                // child is killed on drop
+                Err(_) => warn!("{:?} hook timed out", event),
+            }
+        };
+
+        if self.wait {
+            wait.await
+        } else {
+            tokio::spawn(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, time::Instant};
+
+    #[tokio::test]
+    async fn test_hook_env() {
+        let path = std::env::temp_dir().join(format!("ras_hook_test_{}", std::process::id()));
+
+        let hooks = Hooks {
+            session_start: Some(format!("echo \"$RAS_CLIENT_NAME $RAS_CLIENT_IP\" > {}", path.display())),
+            wait: true,
+            ..Default::default()
+        };
+        hooks
+            .run(
+                HookEvent::SessionStart,
+                vec![("RAS_CLIENT_NAME", "phone".into()), ("RAS_CLIENT_IP", "127.0.0.1".into())],
+            )
+            .await;
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "phone 127.0.0.1\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hook_timeout() {
+        let hooks = Hooks {
+            session_end: Some("sleep 10".into()),
+            timeout: Duration::from_millis(100),
+            wait: true,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        hooks.run(HookEvent::SessionEnd, Vec::new()).await;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
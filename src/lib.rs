@@ -0,0 +1,31 @@
+mod cipher;
+pub mod config;
+mod dacp;
+pub mod decoder;
+mod dmap;
+mod error;
+mod event;
+mod hook;
+mod mdns;
+mod ports;
+mod receiver;
+mod rtp;
+mod rtsp;
+mod rtsp_session;
+pub mod sender;
+mod sessions;
+pub mod sink;
+mod util;
+
+pub use cipher::{generate_key, load_key};
+pub use config::Config;
+pub use dacp::{DacpClient, RemoteCommand};
+pub use decoder::{CodecInfo, Decoder};
+pub use error::{Error, Result};
+pub use event::{Event, EventKind, Metadata, SessionEndReason};
+pub use hook::Hooks;
+pub use mdns::Mdns;
+pub use ports::PortRange;
+pub use receiver::{Receiver, ReceiverBuilder, ReceiverHandle};
+pub use sessions::SessionPolicy;
+pub use sink::{AudioFormat, AudioSink, AudioSinkSession, VolumeCurve};
@@ -1,98 +1,309 @@
-mod cipher;
-mod decoder;
-mod rtp;
-mod rtsp;
-mod rtsp_session;
-mod sink;
-mod util;
-
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-
-use anyhow::Result;
-use clap::Parser;
-use futures::StreamExt;
-use log::{debug, error};
-use mac_address::get_mac_address;
-use tokio::{
-    net::TcpListener,
-    task::{spawn, spawn_local},
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process,
 };
-use tokio_stream::wrappers::TcpListenerStream;
 
+use anyhow::{Context, Result};
+use clap::{ArgAction, Parser, Subcommand};
+use futures::{future, select, FutureExt};
+use log::{debug, error, info, warn};
+use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+
+use ras::{
+    sender::{self, Codec, Sender, WavReader},
+    Config, Mdns, PortRange, SessionPolicy,
+};
+
+// command line flags override environment variables, which override the config file.
+// switches take an optional value, so that `--tcp-transport=false` can turn off what the file turned on
 #[derive(Parser, Debug)]
 struct Args {
-    #[clap(long, default_value = "ras")]
-    server_name: String,
-    #[clap(long, default_value = "rodio")]
-    audio_sink: String,
-    #[clap(long, default_value_t = 7000)]
-    port: u16,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(long, env = "RAS_CONFIG")]
+    config: Option<PathBuf>,
+    #[clap(long, env = "RAS_SERVER_NAME")]
+    server_name: Option<String>,
+    #[clap(long, env = "RAS_AUDIO_SINK")]
+    audio_sink: Option<String>,
+    // mix concurrent sessions into one stream of the audio sink
+    #[clap(long, env = "RAS_MIXER", action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    mixer: Option<bool>,
+    #[clap(long, env = "RAS_PORT")]
+    port: Option<u16>,
+    // comma separated addresses to listen on, both 0.0.0.0 and :: by default
+    #[clap(long, env = "RAS_BIND", value_delimiter = ',')]
+    bind: Vec<IpAddr>,
+    // ports for rtp, control and timing sockets, i.e. 6000-6011. three are used per session
+    #[clap(long, env = "RAS_UDP_PORT_RANGE")]
+    udp_port_range: Option<PortRange>,
+    // pkcs#1 or pkcs#8 pem, defaults to the embedded airport express key
+    #[clap(long, env = "RAS_PRIVATE_KEY")]
+    private_key: Option<PathBuf>,
+    // accept rtp over tcp, advertised as tp=TCP,UDP
+    #[clap(long, env = "RAS_TCP_TRANSPORT", action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    tcp_transport: Option<bool>,
+    // output level in dB at sender's max and min volume
+    #[clap(long, env = "RAS_MAX_VOLUME_DB", allow_hyphen_values = true)]
+    max_volume_db: Option<f32>,
+    #[clap(long, env = "RAS_MIN_VOLUME_DB", allow_hyphen_values = true)]
+    min_volume_db: Option<f32>,
+    // always play at max volume, e.g. when an amplifier controls the volume
+    #[clap(long, env = "RAS_IGNORE_SENDER_VOLUME", action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    ignore_sender_volume: Option<bool>,
+    // seconds without keep-alive or audio from the sender before its session is torn down
+    #[clap(long, env = "RAS_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+    // reject, preempt or mix
+    #[clap(long, env = "RAS_SESSION_POLICY")]
+    session_policy: Option<SessionPolicy>,
+    // shell command run when playback starts
+    #[clap(long, env = "RAS_ON_SESSION_START")]
+    on_session_start: Option<String>,
+    // shell command run when session which started playback ends
+    #[clap(long, env = "RAS_ON_SESSION_END")]
+    on_session_end: Option<String>,
+    // shell command run when track metadata changes
+    #[clap(long, env = "RAS_ON_TRACK_CHANGE")]
+    on_track_change: Option<String>,
+    // hook commands running longer than this many seconds are killed
+    #[clap(long, env = "RAS_HOOK_TIMEOUT")]
+    hook_timeout: Option<u64>,
+    // wait for hook commands to finish before continuing the session
+    #[clap(long, env = "RAS_HOOK_WAIT", action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    hook_wait: Option<bool>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // writes pkcs#8 private key to <output> and public key to <output>.pub
+    Keygen {
+        output: PathBuf,
+        #[clap(long, default_value_t = 2048)]
+        bits: usize,
+    },
+    // streams 16 bit 44100Hz wav file to another airplay receiver
+    Send {
+        file: PathBuf,
+        // receiver name as advertised over mdns, or host:port
+        #[clap(long)]
+        to: String,
+        // alac or l16
+        #[clap(long, default_value = "alac")]
+        codec: Codec,
+        #[clap(long)]
+        encrypt: bool,
+        // airplay volume, -30.0 ~ 0.0
+        #[clap(long, allow_hyphen_values = true)]
+        volume: Option<f32>,
+    },
+}
+
+impl Args {
+    fn config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(server_name) = self.server_name {
+            config.set_name(&server_name);
+        }
+        if let Some(audio_sink) = self.audio_sink {
+            config.set_sink(&audio_sink)?;
+        }
+        if let Some(mixer) = self.mixer {
+            config.sink.mixer = mixer;
+        }
+        if let Some(port) = self.port {
+            config.set_port(port);
+        }
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        if let Some(udp_port_range) = self.udp_port_range {
+            config.udp_port_range = Some(udp_port_range);
+        }
+        if let Some(private_key) = self.private_key {
+            config.private_key = Some(private_key);
+        }
+        if let Some(tcp_transport) = self.tcp_transport {
+            config.tcp_transport = tcp_transport;
+        }
+        if let Some(max_volume_db) = self.max_volume_db {
+            config.volume.max_db = max_volume_db;
+        }
+        if let Some(min_volume_db) = self.min_volume_db {
+            config.volume.min_db = min_volume_db;
+        }
+        if let Some(ignore_sender_volume) = self.ignore_sender_volume {
+            config.volume.ignore_sender = ignore_sender_volume;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.idle_timeout = idle_timeout;
+        }
+        if let Some(session_policy) = self.session_policy {
+            config.session_policy = session_policy;
+        }
+        if let Some(on_session_start) = self.on_session_start {
+            config.hooks.session_start = Some(on_session_start);
+        }
+        if let Some(on_session_end) = self.on_session_end {
+            config.hooks.session_end = Some(on_session_end);
+        }
+        if let Some(on_track_change) = self.on_track_change {
+            config.hooks.track_change = Some(on_track_change);
+        }
+        if let Some(hook_timeout) = self.hook_timeout {
+            config.hooks.timeout = hook_timeout;
+        }
+        if let Some(hook_wait) = self.hook_wait {
+            config.hooks.wait = hook_wait;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+fn keygen(output: &Path, bits: usize) -> Result<()> {
+    let key = ras::generate_key(bits)?;
+    let public_output = PathBuf::from(format!("{}.pub", output.display()));
+
+    // neither file is overwritten, so that an existing pair can't end up mismatched
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    let mut private_options = options.clone();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut private_options, 0o600);
+
+    let mut private_file = private_options
+        .open(output)
+        .with_context(|| format!("Can't create private key {}", output.display()))?;
+    let mut public_file = match options.open(&public_output) {
+        Ok(file) => file,
+        Err(err) => {
+            let _ = std::fs::remove_file(output);
+
+            return Err(err).with_context(|| format!("Can't create public key {}", public_output.display()));
+        }
+    };
+
+    let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+    private_file
+        .write_all(pem.as_bytes())
+        .with_context(|| format!("Can't write private key {}", output.display()))?;
+    public_file
+        .write_all(key.to_public_key().to_public_key_pem(LineEnding::LF)?.as_bytes())
+        .with_context(|| format!("Can't write public key {}", public_output.display()))?;
+
+    println!("Wrote {} and {}", output.display(), public_output.display());
+
+    Ok(())
+}
+
+async fn send(file: &Path, to: &str, codec: Codec, encrypt: bool, volume: Option<f32>) -> Result<()> {
+    let mut source = WavReader::open(file).await?;
+    let addr = sender::resolve(to).await?;
+
+    let mut builder = Sender::builder().codec(codec).encrypt(encrypt);
+    if let Some(volume) = volume {
+        builder = builder.volume(volume);
+    }
+
+    builder.build().send(addr, &mut source, shutdown_signal().map(|_| ())).await
+}
+
+// returns the signal number, SIGINT or SIGTERM
+#[cfg(unix)]
+async fn signal() -> Result<i32> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    select! {
+        _ = interrupt.recv().fuse() => Ok(2),
+        _ = terminate.recv().fuse() => Ok(15),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> Result<i32> {
+    tokio::signal::ctrl_c().await?;
+
+    Ok(2)
+}
+
+// first signal shuts down gracefully, second one exits right away. returns the first signal's number
+async fn shutdown_signal() -> i32 {
+    let number = match signal().await {
+        Ok(number) => number,
+        Err(err) => {
+            warn!("Can't listen for signals: {:?}", err);
+
+            return future::pending().await;
+        }
+    };
+    info!("Received signal {}, shutting down", number);
+
+    tokio::spawn(async {
+        if let Ok(number) = signal().await {
+            process::exit(128 + number);
+        }
+    });
+
+    number
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init();
 
-    let local_set = tokio::task::LocalSet::new();
-
     let args = Args::parse();
 
     debug!("{:?}", args);
 
-    let mac_address = get_mac_address()?.unwrap();
-    debug!("Mac address: {}", mac_address);
-
-    let mdns_join_handle = spawn(async move {
-        let service = simple_mdns::Service::new(
-            "_raop._tcp",
-            &format!("{}@{}", mac_address.to_string().replace(':', ""), args.server_name),
-            args.port,
-            vec![
-                "txtvers=1", // always 1
-                "md=0,1,2",  // metadata type
-                "ss=16",     // sample size
-                "sr=44100",  // sample rate
-                "ch=2",      // channels
-                "et=0,1",    // encryption type
-                "cn=0,1",    // codec type
-                "pw=false",  // has password?
-                "tp=UDP",    // transport protocol
-                "vn=65537",  // required, unknown
-            ],
-        );
-        let server = simple_mdns::Server::new(vec![service]).unwrap();
-        server.serve().await
-    });
-
-    let audio_sink = sink::create(&args.audio_sink);
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), args.port);
+    match &args.command {
+        Some(Command::Keygen { output, bits }) => return keygen(output, *bits),
+        Some(Command::Send {
+            file,
+            to,
+            codec,
+            encrypt,
+            volume,
+        }) => return send(file, to, *codec, *encrypt, *volume).await,
+        None => {}
+    }
 
-    let listener = TcpListener::bind(addr).await?;
-    let mut incoming = TcpListenerStream::new(listener);
+    let config = args.config()?;
 
-    local_set
-        .run_until(async move {
-            let mut id = 1;
-            while let Some(stream) = incoming.next().await {
-                let stream = stream?;
+    debug!("{:?}", config);
 
-                let audio_session = audio_sink.start()?;
-                spawn_local(async move {
-                    let result = rtsp_session::RtspSession::start(id, stream, audio_session, mac_address).await;
+    // receivers share one mdns responder, each one has its own port and sink
+    let mdns = Mdns::new();
+    let mut handles = Vec::new();
+    for config in config.receivers() {
+        let receiver = config.builder()?.mdns(mdns.clone()).sink(config.create_sink()?).build()?;
 
-                    if let Err(err) = result {
-                        error!("{:?}", err);
-                    }
-                });
+        handles.push(receiver.start().await?);
+    }
 
-                id += 1;
-            }
+    let shutdown = shutdown_signal().shared();
+    let results = future::join_all(handles.into_iter().map(|handle| handle.wait_for(shutdown.clone().map(|_| ())))).await;
 
-            mdns_join_handle.await??;
+    // every receiver is shut down even if another one failed, e.g. flushing its sink
+    let mut code = shutdown.peek().map_or(0, |number| 128 + number);
+    for result in results {
+        if let Err(err) = result {
+            error!("{:?}", err);
+            code = 1;
+        }
+    }
 
-            anyhow::Ok(())
-        })
-        .await?;
-
-    Ok(())
+    process::exit(code)
 }
@@ -0,0 +1,450 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    str,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, trace, warn};
+use mdns_sd::{DaemonEvent, IfKind, Receiver, ScopedIp, ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::{net::UdpSocket, time::timeout};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// in question, asks for unicast response. in record, means cache flush
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+const QUERY_ATTEMPTS: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// dns-sd service instance, i.e. `<instance>.<service_type>.local`
+#[derive(Clone, Debug)]
+pub struct MdnsService {
+    pub service_type: String,
+    pub instance: String,
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+impl MdnsService {
+    // unspecified bind address announces every address of its family
+    fn info(&self, hostname: &str, addrs: &[IpAddr]) -> Result<ServiceInfo> {
+        let txt = self.txt.iter().map(|x| x.split_once('=').unwrap_or((x, ""))).collect::<Vec<_>>();
+        let specified = addrs.iter().copied().filter(|x| !x.is_unspecified()).collect::<Vec<_>>();
+
+        let mut info = ServiceInfo::new(
+            &format!("{}.local.", self.service_type),
+            &self.instance,
+            &format!("{hostname}.local."),
+            &specified[..],
+            self.port,
+            &txt[..],
+        )?;
+        if specified.len() < addrs.len() {
+            info = info.enable_addr_auto();
+        }
+        info.set_interfaces(
+            addrs
+                .iter()
+                .map(|x| match x {
+                    IpAddr::V4(x) if x.is_unspecified() => IfKind::IPv4,
+                    IpAddr::V6(x) if x.is_unspecified() => IfKind::IPv6,
+                    x => IfKind::Addr(*x),
+                })
+                .collect(),
+        );
+
+        Ok(info)
+    }
+}
+
+// mdns daemon shared by receivers, with number of services it serves
+#[derive(Clone, Default)]
+pub struct Mdns {
+    daemon: Arc<Mutex<Option<(ServiceDaemon, usize)>>>,
+}
+
+impl Mdns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // first service starts the daemon, it's shut down after the last one is gone
+    pub(crate) fn register(&self, hostname: &str, service: MdnsService, addrs: &[IpAddr]) -> Result<MdnsRegistration> {
+        let info = service.info(hostname, addrs)?;
+        let fullname = info.get_fullname().to_owned();
+
+        let mut daemon = self.daemon.lock().unwrap();
+        if daemon.is_none() {
+            *daemon = Some((ServiceDaemon::new()?, 0));
+        }
+        let (daemon, count) = daemon.as_mut().unwrap();
+
+        let events = daemon.monitor()?;
+        daemon.register(info)?;
+        *count += 1;
+
+        Ok(MdnsRegistration {
+            mdns: self.clone(),
+            daemon: daemon.clone(),
+            fullname,
+            events,
+            withdrawn: false,
+        })
+    }
+
+    fn release(&self) {
+        let mut daemon = self.daemon.lock().unwrap();
+        if let Some((_, count)) = daemon.as_mut() {
+            *count -= 1;
+            if *count == 0 {
+                let _ = daemon.take().unwrap().0.shutdown();
+            }
+        }
+    }
+}
+
+// service served until withdrawn or dropped
+pub(crate) struct MdnsRegistration {
+    mdns: Mdns,
+    daemon: ServiceDaemon,
+    fullname: String,
+    events: Receiver<DaemonEvent>,
+    withdrawn: bool,
+}
+
+impl MdnsRegistration {
+    // completes when the daemon stopped
+    pub async fn closed(&self) {
+        while let Ok(event) = self.events.recv_async().await {
+            match event {
+                DaemonEvent::NameChange(change) => info!("Mdns name {} is taken, renamed to {}", change.original, change.new_name),
+                DaemonEvent::Error(err) => warn!("Mdns error: {:?}", err),
+                _ => {}
+            }
+        }
+    }
+
+    // waits for goodbye to be sent
+    pub async fn withdraw(mut self) {
+        self.withdrawn = true;
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => {
+                let _ = status.recv_async().await;
+            }
+            Err(err) => warn!("Can't withdraw {}: {:?}", self.fullname, err),
+        }
+    }
+}
+
+impl Drop for MdnsRegistration {
+    fn drop(&mut self) {
+        if !self.withdrawn {
+            let _ = self.daemon.unregister(&self.fullname);
+        }
+        self.mdns.release();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub port: u16,
+    pub target: String,
+}
+
+// one-shot legacy unicast query, sent to multicast group and directly to the host we expect the answer from
+pub async fn query_srv(name: &str, host: IpAddr) -> Result<SrvRecord> {
+    let socket = match host {
+        IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    let query = build_query(name, TYPE_SRV)?;
+
+    for _ in 0..QUERY_ATTEMPTS {
+        if host.is_ipv4() {
+            socket.send_to(&query, (MDNS_ADDR, MDNS_PORT)).await?;
+        }
+        socket.send_to(&query, SocketAddr::new(host, MDNS_PORT)).await?;
+
+        let mut buf = [0; 9000];
+        while let Ok(result) = timeout(QUERY_TIMEOUT, socket.recv_from(&mut buf)).await {
+            let (length, addr) = result?;
+            trace!("mdns response from {}: {:?}", addr, &buf[..length]);
+
+            match parse_srv(&buf[..length], name) {
+                Ok(Some(record)) => {
+                    debug!("Resolved {} to {:?}", name, record);
+                    return Ok(record);
+                }
+                Ok(None) => {}
+                Err(err) => debug!("Invalid mdns response from {}: {:?}", addr, err),
+            }
+        }
+    }
+
+    Err(anyhow!("Can't resolve {name}"))
+}
+
+// browses the service type for an instance named `name`, raop instances also match by the part after '@'
+pub async fn resolve(service_type: &str, name: &str) -> Result<SocketAddr> {
+    let daemon = ServiceDaemon::new()?;
+    let result = timeout(RESOLVE_TIMEOUT, browse(&daemon, service_type, name)).await;
+    if let Ok(status) = daemon.shutdown() {
+        let _ = status.recv_async().await;
+    }
+
+    result.map_err(|_| anyhow!("Can't find {name} in {service_type}"))?
+}
+
+async fn browse(daemon: &ServiceDaemon, service_type: &str, name: &str) -> Result<SocketAddr> {
+    let events = daemon.browse(service_type)?;
+    let suffix = format!(".{service_type}");
+
+    let matches = |instance: &str| {
+        let label = instance.strip_suffix(&suffix).unwrap_or(instance);
+        label.eq_ignore_ascii_case(name) || label.split_once('@').is_some_and(|(_, x)| x.eq_ignore_ascii_case(name))
+    };
+
+    while let Ok(event) = events.recv_async().await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        if !matches(service.get_fullname()) {
+            continue;
+        }
+
+        let addrs = service
+            .get_addresses()
+            .iter()
+            .map(|x| match x {
+                ScopedIp::V6(addr) => (IpAddr::V6(*addr.addr()), addr.scope_id().index),
+                addr => (addr.to_ip_addr(), 0),
+            })
+            .collect::<Vec<_>>();
+
+        // other responses may still have it
+        match select_addr(&addrs, service.get_port()) {
+            Some(addr) => {
+                debug!("Resolved {} to {}", service.get_fullname(), addr);
+                return Ok(addr);
+            }
+            None => debug!("No usable address for {} in {:?}", service.get_fullname(), addrs),
+        }
+    }
+
+    Err(anyhow!("Mdns daemon stopped"))
+}
+
+// ipv4 first, link local ipv6 is only reachable through the interface it was found on
+fn select_addr(addrs: &[(IpAddr, u32)], port: u16) -> Option<SocketAddr> {
+    let v4 = addrs.iter().find(|(x, _)| x.is_ipv4()).map(|(x, _)| SocketAddr::new(*x, port));
+    let v6 = addrs.iter().filter_map(|(x, scope_id)| match x {
+        IpAddr::V6(addr) => Some((*addr, *scope_id)),
+        IpAddr::V4(_) => None,
+    });
+    let global = v6
+        .clone()
+        .find(|(x, _)| !x.is_unicast_link_local())
+        .map(|(x, _)| SocketAddr::new(IpAddr::V6(x), port));
+    let link_local = v6
+        .clone()
+        .find(|(x, scope_id)| x.is_unicast_link_local() && *scope_id != 0)
+        .map(|(x, scope_id)| SocketAddr::V6(SocketAddrV6::new(x, port, 0, scope_id)));
+
+    v4.or(global).or(link_local)
+}
+
+fn build_query(name: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut packet = vec![
+        0, 0, // id
+        0, 0, // flags, standard query
+        0, 1, // questions
+        0, 0, // answers
+        0, 0, // authorities
+        0, 0, // additionals
+    ];
+
+    write_name(&mut packet, name)?;
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&(CLASS_IN | CLASS_UNICAST_RESPONSE).to_be_bytes());
+
+    Ok(packet)
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("Invalid dns name {name:?}"));
+        }
+
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    Ok(())
+}
+
+// returns name and offset right after the name
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+
+    // every pointer has to go backward, so it can't loop forever
+    let mut limit = offset;
+    loop {
+        let length = *packet.get(offset).ok_or_else(|| anyhow!("Truncated dns name"))? as usize;
+
+        if length & 0xc0 == 0xc0 {
+            let low = *packet.get(offset + 1).ok_or_else(|| anyhow!("Truncated dns name"))? as usize;
+            let pointer = ((length & 0x3f) << 8) | low;
+            if pointer >= limit {
+                return Err(anyhow!("Invalid dns name pointer"));
+            }
+
+            end.get_or_insert(offset + 2);
+            limit = pointer;
+            offset = pointer;
+        } else if length == 0 {
+            let end = end.unwrap_or(offset + 1);
+            return Ok((labels.join("."), end));
+        } else {
+            let label = packet
+                .get(offset + 1..offset + 1 + length)
+                .ok_or_else(|| anyhow!("Truncated dns label"))?;
+            labels.push(str::from_utf8(label)?.to_owned());
+
+            offset += 1 + length;
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16> {
+    let data = packet.get(offset..offset + 2).ok_or_else(|| anyhow!("Truncated dns packet"))?;
+
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+fn parse_srv(packet: &[u8], name: &str) -> Result<Option<SrvRecord>> {
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    for _ in 0..records {
+        let (record_name, next) = read_name(packet, offset)?;
+        let record_type = read_u16(packet, next)?;
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        if packet.len() < data + length {
+            return Err(anyhow!("Truncated dns record"));
+        }
+
+        if record_type == TYPE_SRV && record_name.eq_ignore_ascii_case(name.trim_end_matches('.')) {
+            return Ok(Some(SrvRecord {
+                port: read_u16(packet, data + 4)?,
+                target: read_name(packet, data + 6)?.0,
+            }));
+        }
+
+        offset = data + length;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_query() -> Result<()> {
+        let query = build_query("a._dacp._tcp.local", TYPE_SRV)?;
+
+        assert_eq!(&query[..12], &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x01a\x05_dacp\x04_tcp\x05local\x00\x00\x21\x80\x01");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_srv_response() -> Result<()> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+
+        // txt record for the same name, should be skipped
+        write_name(&mut packet, "iTunes_Ctrl_1234._dacp._tcp.local")?;
+        packet.extend_from_slice(&[0, 16, 0x80, 1, 0, 0, 0x11, 0x94, 0, 1, 0]);
+
+        // srv record with compressed name and target
+        packet.extend_from_slice(&[0xc0, 12, 0, 33, 0x80, 1, 0, 0, 0, 0x78, 0, 14, 0, 0, 0, 0, 0x0d, 0xfb]);
+        packet.extend_from_slice(b"\x05phone\xc0\x28");
+
+        let record = parse_srv(&packet, "itunes_ctrl_1234._dacp._tcp.local")?;
+
+        assert_eq!(
+            record,
+            Some(SrvRecord {
+                port: 3579,
+                target: "phone.local".into()
+            })
+        );
+        assert_eq!(parse_srv(&packet, "other._dacp._tcp.local")?, None);
+        assert!(parse_srv(&packet[..packet.len() - 3], "other._dacp._tcp.local").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_select_addr() -> Result<()> {
+        let v4 = ("192.168.0.2".parse()?, 0);
+        let global = ("2001:db8::2".parse()?, 3);
+        let link_local = ("fe80::1".parse()?, 3);
+
+        assert_eq!(select_addr(&[link_local, global, v4], 7000), Some("192.168.0.2:7000".parse()?));
+        assert_eq!(select_addr(&[link_local, global], 7000), Some("[2001:db8::2]:7000".parse()?));
+
+        // link local address is scoped to the interface it was found on
+        assert_eq!(
+            select_addr(&[link_local], 7000),
+            Some(SocketAddr::V6(SocketAddrV6::new("fe80::1".parse()?, 7000, 0, 3)))
+        );
+        assert_eq!(select_addr(&[("fe80::1".parse()?, 0)], 7000), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_service_info() -> Result<()> {
+        let service = MdnsService {
+            service_type: "_raop._tcp".into(),
+            instance: "001122334455@Living Room".into(),
+            port: 7000,
+            txt: vec!["txtvers=1".into(), "tp=UDP".into()],
+        };
+
+        let info = service.info("ras-001122334455", &["192.168.0.2".parse()?, "fe80::1".parse()?])?;
+        assert_eq!(info.get_fullname(), "001122334455@Living Room._raop._tcp.local.");
+        assert_eq!(info.get_hostname(), "ras-001122334455.local.");
+        assert_eq!(info.get_property_val_str("tp"), Some("UDP"));
+        // aaaa record is published along with a
+        assert!(info.get_addresses().contains(&"fe80::1".parse()?));
+        assert!(info.get_addresses().contains(&"192.168.0.2".parse()?));
+        assert!(!info.is_addr_auto());
+
+        // every address of the host
+        let info = service.info("ras-001122334455", &[Ipv6Addr::UNSPECIFIED.into()])?;
+        assert!(info.get_addresses().is_empty());
+        assert!(info.is_addr_auto());
+
+        Ok(())
+    }
+}
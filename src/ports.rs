@@ -0,0 +1,189 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    net::{self, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Error, Result};
+use log::debug;
+use serde::Deserialize;
+use tokio::net::{TcpListener, UdpSocket};
+
+// inclusive port range in `6000-6010` form
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid port range {s:?}, expected <start>-<end>"))?;
+        let start = start.trim().parse().map_err(|_| anyhow!("Invalid port range start {start:?}"))?;
+        let end = end.trim().parse().map_err(|_| anyhow!("Invalid port range end {end:?}"))?;
+
+        if start == 0 || start > end {
+            return Err(anyhow!("Invalid port range {s:?}"));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+// ports for rtp, control and timing sockets or tcp data connection. any free port is used without range
+pub(crate) struct Ports {
+    range: Option<PortRange>,
+    used: Mutex<BTreeSet<u16>>,
+}
+
+impl Ports {
+    pub fn new(range: Option<PortRange>) -> Self {
+        Self {
+            range,
+            used: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    // fails when there aren't enough free ports left in the range
+    pub fn bind_udp(&self, addr: SocketAddr, count: usize) -> Result<Vec<UdpSocket>> {
+        self.allocate(addr, count, bind_udp)
+    }
+
+    pub fn listen_tcp(&self, addr: SocketAddr) -> Result<TcpListener> {
+        Ok(self.allocate(addr, 1, listen_tcp)?.remove(0))
+    }
+
+    fn allocate<T, F>(&self, addr: SocketAddr, count: usize, bind: F) -> Result<Vec<T>>
+    where
+        F: Fn(SocketAddr) -> Result<T>,
+    {
+        let range = if let Some(range) = self.range {
+            range
+        } else {
+            return (0..count).map(|_| bind(with_port(addr, 0))).collect();
+        };
+
+        let mut used = self.used.lock().unwrap();
+        let mut sockets = Vec::with_capacity(count);
+        let mut ports = Vec::with_capacity(count);
+        for port in range.start..=range.end {
+            if sockets.len() == count {
+                break;
+            }
+            if used.contains(&port) {
+                continue;
+            }
+
+            match bind(with_port(addr, port)) {
+                Ok(socket) => {
+                    sockets.push(socket);
+                    ports.push(port);
+                }
+                // taken by another process, try next one
+                Err(err) => debug!("Can't bind port {}: {:?}", port, err),
+            }
+        }
+
+        if sockets.len() < count {
+            return Err(anyhow!("No free port in {range}"));
+        }
+        used.extend(ports);
+
+        Ok(sockets)
+    }
+
+    pub fn release(&self, ports: &[u16]) {
+        let mut used = self.used.lock().unwrap();
+        for port in ports {
+            used.remove(port);
+        }
+    }
+}
+
+// set_port keeps scope of ipv6 link local address
+fn with_port(mut addr: SocketAddr, port: u16) -> SocketAddr {
+    addr.set_port(port);
+    addr
+}
+
+fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = net::UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+fn listen_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    Ok(TcpListener::from_std(listener)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_port_range() -> Result<()> {
+        assert_eq!("6000-6010".parse::<PortRange>()?, PortRange { start: 6000, end: 6010 });
+        assert!("6010-6000".parse::<PortRange>().is_err());
+        assert!("6000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ports() -> Result<()> {
+        let addr = "127.0.0.1:0".parse()?;
+
+        // other processes may use some of the ports, look for a range we can use
+        let (ports, sockets, start) = loop {
+            let start = net::UdpSocket::bind(addr)?.local_addr()?.port().min(u16::MAX - 3);
+            let ports = Ports::new(Some(PortRange { start, end: start + 3 }));
+
+            if let Ok(sockets) = ports.bind_udp(addr, 3) {
+                break (ports, sockets, start);
+            }
+        };
+        let bound = sockets.iter().map(|x| x.local_addr().unwrap().port()).collect::<Vec<_>>();
+        assert!(bound.iter().all(|x| (start..=start + 3).contains(x)));
+
+        // only one left
+        assert!(ports.bind_udp(addr, 3).is_err());
+
+        drop(sockets);
+        ports.release(&bound);
+        let sockets = ports.bind_udp(addr, 2)?;
+
+        // tcp data connection shares the range
+        assert!(ports.listen_tcp(addr)?.local_addr()?.port() >= start);
+        assert!(ports.bind_udp(addr, 2).is_err());
+        drop(sockets);
+
+        Ok(())
+    }
+}
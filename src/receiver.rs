@@ -0,0 +1,456 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use futures::{future, select, stream, FutureExt, StreamExt};
+use log::{debug, error, warn};
+use mac_address::{get_mac_address, MacAddress};
+use rsa::RsaPrivateKey;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, oneshot, watch},
+    task::{spawn, JoinHandle},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+
+use crate::{
+    cipher,
+    dacp::{DacpClient, RemoteCommand},
+    decoder::{CodecInfo, Decoder, DecoderFactory},
+    event::Event,
+    hook::Hooks,
+    mdns::{Mdns, MdnsService},
+    ports::{PortRange, Ports},
+    rtsp_session::RtspSession,
+    sessions::{SessionPolicy, Sessions},
+    sink::{AudioSink, VolumeCurve},
+};
+
+// state shared by every session of a receiver
+pub(crate) struct ReceiverContext {
+    pub mac_address: MacAddress,
+    pub key: Arc<RsaPrivateKey>,
+    pub decoders: HashMap<String, DecoderFactory>,
+    pub events: broadcast::Sender<Event>,
+    pub sessions: Sessions,
+    pub ports: Ports,
+    pub tcp_transport: bool,
+    pub hooks: Hooks,
+    pub latency: Option<u32>,
+    pub volume_curve: VolumeCurve,
+    pub idle_timeout: Duration,
+    // set once receiver is shutting down, sessions then end themselves
+    pub shutdown: watch::Sender<bool>,
+}
+
+// senders send OPTIONS or GET_PARAMETER every few seconds as keep-alive, even while paused
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// events are dropped for subscribers lagging behind more than this
+const EVENT_CAPACITY: usize = 256;
+
+const DEFAULT_TXT: [(&str, &str); 10] = [
+    ("txtvers", "1"), // always 1
+    ("md", "0,1,2"),  // metadata type
+    ("ss", "16"),     // sample size
+    ("sr", "44100"),  // sample rate
+    ("ch", "2"),      // channels
+    ("et", "0,1"),    // encryption type
+    ("cn", "0,1"),    // codec type
+    ("pw", "false"),  // has password?
+    ("tp", "UDP"),    // transport protocol
+    ("vn", "65537"),  // required, unknown
+];
+
+pub struct ReceiverBuilder {
+    name: String,
+    port: u16,
+    bind: Vec<IpAddr>,
+    sink: Option<Arc<dyn AudioSink>>,
+    key: Option<RsaPrivateKey>,
+    mac_address: Option<MacAddress>,
+    session_policy: SessionPolicy,
+    udp_port_range: Option<PortRange>,
+    tcp_transport: bool,
+    hooks: Hooks,
+    latency: Option<u32>,
+    volume_curve: VolumeCurve,
+    idle_timeout: Duration,
+    txt: Vec<(String, String)>,
+    decoders: HashMap<String, DecoderFactory>,
+    mdns: Option<Mdns>,
+}
+
+impl ReceiverBuilder {
+    fn new() -> Self {
+        Self {
+            name: "ras".into(),
+            port: 7000,
+            bind: Vec::new(),
+            sink: None,
+            key: None,
+            mac_address: None,
+            session_policy: SessionPolicy::default(),
+            udp_port_range: None,
+            tcp_transport: false,
+            hooks: Hooks::default(),
+            latency: None,
+            volume_curve: VolumeCurve::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            txt: DEFAULT_TXT.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            decoders: HashMap::new(),
+            mdns: None,
+        }
+    }
+
+    // service name shown on senders
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    // rtsp port, 0 to pick any free port
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    // adds address to listen on, defaults to both 0.0.0.0 and ::
+    pub fn bind(mut self, addr: IpAddr) -> Self {
+        self.bind.push(addr);
+        self
+    }
+
+    pub fn sink(mut self, sink: Arc<dyn AudioSink>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    // rsa key used for apple challenge and stream key exchange, defaults to the airport express key
+    pub fn key(mut self, key: RsaPrivateKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    // defaults to the mac address of the first interface
+    pub fn mac_address(mut self, mac_address: MacAddress) -> Self {
+        self.mac_address = Some(mac_address);
+        self
+    }
+
+    // what to do with senders connecting while another one is playing
+    pub fn session_policy(mut self, session_policy: SessionPolicy) -> Self {
+        self.session_policy = session_policy;
+        self
+    }
+
+    // ports for rtp, control and timing sockets, three per session. any free port by default
+    pub fn udp_port_range(mut self, range: PortRange) -> Self {
+        self.udp_port_range = Some(range);
+        self
+    }
+
+    // accepts RTP/AVP/TCP, interleaved on rtsp connection or on separate data connection
+    pub fn tcp_transport(mut self, tcp_transport: bool) -> Self {
+        self.tcp_transport = tcp_transport;
+        self
+    }
+
+    // commands to run on session start, end and track change
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    // Audio-Latency reported to senders, in samples
+    pub fn latency(mut self, latency: u32) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    // maps sender volume to gain applied to audio before it's written to sink sessions
+    pub fn volume_curve(mut self, volume_curve: VolumeCurve) -> Self {
+        self.volume_curve = volume_curve;
+        self
+    }
+
+    // sessions without any rtsp request or rtp packet for this long are torn down
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    // adds or overrides txt record field
+    pub fn txt(mut self, key: &str, value: &str) -> Self {
+        if let Some(field) = self.txt.iter_mut().find(|(x, _)| x == key) {
+            field.1 = value.into();
+        } else {
+            self.txt.push((key.into(), value.into()));
+        }
+        self
+    }
+
+    // registers decoder for sdp codec name, takes precedence over builtin decoders
+    pub fn decoder<F>(mut self, codec: &str, factory: F) -> Self
+    where
+        F: Fn(&CodecInfo) -> crate::Result<Box<dyn Decoder>> + Send + Sync + 'static,
+    {
+        self.decoders.insert(codec.into(), Arc::new(factory));
+        self
+    }
+
+    // announce with a responder shared with other receivers, each receiver runs its own by default
+    pub fn mdns(mut self, mdns: Mdns) -> Self {
+        self.mdns = Some(mdns);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Receiver> {
+        let sink = self.sink.ok_or_else(|| anyhow!("Audio sink is not set"))?;
+        let mac_address = match self.mac_address {
+            Some(mac_address) => mac_address,
+            None => get_mac_address()?.ok_or_else(|| anyhow!("Can't find mac address"))?,
+        };
+        debug!("Mac address: {}", mac_address);
+
+        // unless overridden with txt()
+        if self.tcp_transport {
+            if let Some(field) = self.txt.iter_mut().find(|(key, value)| key == "tp" && value == "UDP") {
+                field.1 = "TCP,UDP".into();
+            }
+        }
+
+        Ok(Receiver {
+            name: self.name,
+            port: self.port,
+            bind: self.bind,
+            txt: self.txt.into_iter().map(|(key, value)| format!("{key}={value}")).collect(),
+            sink,
+            mdns: self.mdns.unwrap_or_default(),
+            context: Arc::new(ReceiverContext {
+                mac_address,
+                key: Arc::new(self.key.unwrap_or_else(|| cipher::KEY.clone())),
+                decoders: self.decoders,
+                events: broadcast::channel(EVENT_CAPACITY).0,
+                sessions: Sessions::new(self.session_policy),
+                ports: Ports::new(self.udp_port_range),
+                tcp_transport: self.tcp_transport,
+                hooks: self.hooks,
+                latency: self.latency,
+                volume_curve: self.volume_curve,
+                idle_timeout: self.idle_timeout,
+                shutdown: watch::channel(false).0,
+            }),
+        })
+    }
+}
+
+pub struct Receiver {
+    name: String,
+    port: u16,
+    bind: Vec<IpAddr>,
+    txt: Vec<String>,
+    sink: Arc<dyn AudioSink>,
+    mdns: Mdns,
+    context: Arc<ReceiverContext>,
+}
+
+impl Receiver {
+    pub fn builder() -> ReceiverBuilder {
+        ReceiverBuilder::new()
+    }
+
+    // has to be called inside of tokio runtime, sessions run on its worker threads
+    pub async fn start(self) -> Result<ReceiverHandle> {
+        let listeners = self.listen()?;
+        let local_addrs = listeners.iter().map(|x| x.local_addr()).collect::<Result<Vec<_>, _>>()?;
+
+        let context = self.context.clone();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let join_handle = spawn(self.run(listeners, shutdown_receiver));
+
+        Ok(ReceiverHandle {
+            local_addrs,
+            context,
+            shutdown_sender,
+            join_handle,
+        })
+    }
+
+    fn listen(&self) -> Result<Vec<TcpListener>> {
+        // default dual stack listener shouldn't fail on hosts without ipv6
+        let (addrs, optional_v6) = if self.bind.is_empty() {
+            (vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)], true)
+        } else {
+            (self.bind.clone(), false)
+        };
+
+        let mut listeners = Vec::new();
+        let mut port = self.port;
+        for addr in addrs {
+            match listen(SocketAddr::new(addr, port)) {
+                Ok(listener) => {
+                    // every listener uses the port picked by the first one, as we can announce only one port
+                    port = listener.local_addr()?.port();
+                    debug!("Listening on {}", listener.local_addr()?);
+
+                    listeners.push(listener);
+                }
+                Err(err) if optional_v6 && addr.is_ipv6() => warn!("Can't listen on {}: {:?}", addr, err),
+                Err(err) => return Err(err).with_context(|| format!("Can't listen on {}", SocketAddr::new(addr, port))),
+            }
+        }
+
+        Ok(listeners)
+    }
+
+    // raop instance name, `<mac>@<name>`
+    pub(crate) fn instance(&self) -> String {
+        format!("{}@{}", self.mac_address(), self.name)
+    }
+
+    fn mac_address(&self) -> String {
+        self.context.mac_address.to_string().replace(':', "")
+    }
+
+    async fn run(self, listeners: Vec<TcpListener>, shutdown: oneshot::Receiver<()>) -> Result<()> {
+        let local_addrs = listeners.iter().map(|x| x.local_addr()).collect::<Result<Vec<_>, _>>()?;
+        let service = MdnsService {
+            service_type: "_raop._tcp".into(),
+            instance: self.instance(),
+            port: local_addrs[0].port(),
+            txt: self.txt.clone(),
+        };
+        let hostname = format!("ras-{}", self.mac_address());
+        let addrs = local_addrs.iter().map(|x| x.ip()).collect::<Vec<_>>();
+        let registration = self.mdns.register(&hostname, service, &addrs)?;
+
+        let mut incoming = stream::select_all(listeners.into_iter().map(TcpListenerStream::new)).fuse();
+        let mut shutdown = shutdown.fuse();
+        let mut mdns = Box::pin(registration.closed().fuse());
+        let mut sessions: Vec<JoinHandle<()>> = Vec::new();
+
+        let mut id = 1;
+        loop {
+            select! {
+                stream = incoming.next() => {
+                    let stream = if let Some(stream) = stream { stream? } else { break };
+
+                    let sink = self.sink.clone();
+                    let context = self.context.clone();
+                    sessions.retain(|x| !x.is_finished());
+                    sessions.push(spawn(async move {
+                        let result = RtspSession::start(id, stream, sink, context).await;
+
+                        if let Err(err) = result {
+                            error!("{:?}", err);
+                        }
+                    }));
+
+                    id += 1;
+                }
+                _ = mdns => return Err(anyhow!("Mdns daemon stopped")),
+                _ = shutdown => break,
+            }
+        }
+
+        drop(mdns);
+        drop(incoming);
+
+        // withdraw the service first, so that no sender picks us while sessions wind down
+        registration.withdraw().await;
+
+        // sessions fade out and run their end hooks
+        self.context.shutdown.send_replace(true);
+        for result in future::join_all(sessions).await {
+            if let Err(err) = result {
+                error!("Session task failed: {:?}", err);
+            }
+        }
+
+        Ok(self.sink.flush()?)
+    }
+}
+
+fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // so that 0.0.0.0 and :: can be bound separately on the same port
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+pub struct ReceiverHandle {
+    local_addrs: Vec<SocketAddr>,
+    context: Arc<ReceiverContext>,
+    shutdown_sender: oneshot::Sender<()>,
+    join_handle: JoinHandle<Result<()>>,
+}
+
+impl ReceiverHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    // every address we listen on, they all share the same port
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    // events of every session, subscribe before sessions start to not miss any
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.context.events.subscribe()
+    }
+
+    // sends remote control command to the sender of given session, or of the most recent session
+    pub async fn remote_command(&self, session_id: Option<u32>, command: RemoteCommand) -> Result<()> {
+        let info = self.context.sessions.find(session_id).ok_or_else(|| anyhow!("No such session"))?;
+
+        let (dacp_id, active_remote) = info
+            .dacp_id
+            .zip(info.active_remote)
+            .ok_or_else(|| anyhow!("Sender doesn't support remote control"))?;
+
+        DacpClient::discover(info.addr.ip(), &dacp_id, &active_remote).await?.send(command).await
+    }
+
+    // stops accepting new connections, withdraws the service and waits for every session to end
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown_sender.send(());
+
+        self.join_handle.await?
+    }
+
+    // waits until the receiver stops by itself or `signal` completes, in which case the receiver is shut down
+    pub async fn wait_for(mut self, signal: impl Future<Output = ()>) -> Result<()> {
+        let stopped = select! {
+            result = (&mut self.join_handle).fuse() => Some(result),
+            _ = Box::pin(signal.fuse()) => None,
+        };
+
+        match stopped {
+            Some(result) => result?,
+            None => self.shutdown().await,
+        }
+    }
+
+    // waits until the receiver stops by itself, dropping the handle without waiting shuts the receiver down
+    pub async fn wait(self) -> Result<()> {
+        let result = self.join_handle.await?;
+        drop(self.shutdown_sender);
+
+        result
+    }
+}
@@ -1,14 +1,27 @@
-use anyhow::{anyhow, Result};
-use bytes::{Buf, BytesMut};
+use bytes::BytesMut;
 use rtp_rs::RtpReader;
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::{Error, Result};
+
+// raop payload types sent besides audio
+pub const PAYLOAD_TYPE_TIMING_REQUEST: u8 = 82;
+pub const PAYLOAD_TYPE_TIMING_RESPONSE: u8 = 83;
+pub const PAYLOAD_TYPE_SYNC: u8 = 84;
+pub const PAYLOAD_TYPE_RETRANSMIT: u8 = 86;
 
 pub struct RtpPacket {
     pub payload_type: u8,
+    // set on the first packet after RECORD or FLUSH
+    pub marker: bool,
+    pub sequence: u16,
+    pub timestamp: u32,
     pub payload: Vec<u8>,
 }
 
 pub struct RtpControlPacket {
+    // first sync packet after RECORD or FLUSH has extension bit set
+    pub first: bool,
     pub timestamp: u32,
     pub current_time_seconds: u32,
     pub current_time_fraction: u32,
@@ -19,33 +32,39 @@ pub struct RtpCodec {}
 
 impl Decoder for RtpCodec {
     type Item = RtpPacket;
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let (packet, length) = {
-            let reader = RtpReader::new(src);
-            if let Err(e) = reader {
-                return match e {
-                    rtp_rs::RtpReaderError::BufferTooShort(_) => Ok(None),
-                    rtp_rs::RtpReaderError::UnsupportedVersion(_) => Err(anyhow!("{:?}", e)),
-                    rtp_rs::RtpReaderError::HeadersTruncated { .. } => Ok(None),
-                    rtp_rs::RtpReaderError::PaddingLengthInvalid(_) => Ok(None),
-                };
-            }
-
-            let reader = reader.unwrap();
-            Ok::<_, Self::Error>((
-                RtpPacket {
-                    payload_type: reader.payload_type(),
-                    payload: reader.payload().to_vec(),
-                },
-                reader.payload_offset() + reader.payload().len(),
-            ))
-        }?;
-
-        src.advance(length);
-
-        Ok(Some(packet))
+        if src.is_empty() {
+            return Ok(None);
+        }
+        // whole datagram or tcp frame is a packet, consumed even if it's invalid so that next one can be read
+        let data = src.split();
+        let reader = RtpReader::new(&data).map_err(|err| Error::Rtp(format!("{:?}", err)))?;
+
+        Ok(Some(RtpPacket {
+            payload_type: reader.payload_type(),
+            marker: reader.mark(),
+            sequence: reader.sequence_number().into(),
+            timestamp: reader.timestamp(),
+            payload: reader.payload().to_vec(),
+        }))
+    }
+}
+
+impl Encoder<RtpPacket> for RtpCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: RtpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let marker = if item.marker { 0x80 } else { 0 };
+
+        dst.extend_from_slice(&[0x80, marker | item.payload_type]);
+        dst.extend_from_slice(&item.sequence.to_be_bytes());
+        dst.extend_from_slice(&item.timestamp.to_be_bytes());
+        dst.extend_from_slice(&[0; 4]); // ssrc
+        dst.extend_from_slice(&item.payload);
+
+        Ok(())
     }
 }
 
@@ -53,7 +72,7 @@ pub struct RtpControlCodec {}
 
 impl Decoder for RtpControlCodec {
     type Item = RtpControlPacket;
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // control packet looks likes regular rtp packet, but it comes without ssrc part.
@@ -67,13 +86,17 @@ impl Decoder for RtpControlCodec {
             next_timestamp: [u8; 4],
         }
 
-        if src.len() < core::mem::size_of::<RawRtpControlPacket>() {
+        if src.is_empty() {
             return Ok(None);
         }
+        let src = src.split();
+        if src.len() < core::mem::size_of::<RawRtpControlPacket>() {
+            return Err(Error::Rtp("Truncated control packet".into()));
+        }
         let data = unsafe { &*(src.as_ptr() as *const RawRtpControlPacket) };
-        src.advance(core::mem::size_of::<RawRtpControlPacket>());
 
         Ok(Some(RtpControlPacket {
+            first: data.rtp_header[0] & 0x10 != 0,
             timestamp: u32::from_be_bytes(data.rtp_timestamp),
             current_time_seconds: u32::from_be_bytes(data.ntp_time_seconds),
             current_time_fraction: u32::from_be_bytes(data.ntp_time_fraction),
@@ -82,6 +105,69 @@ impl Decoder for RtpControlCodec {
     }
 }
 
+impl Encoder<RtpControlPacket> for RtpControlCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: RtpControlPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let extension = if item.first { 0x10 } else { 0 };
+
+        dst.extend_from_slice(&[0x80 | extension, 0x80 | PAYLOAD_TYPE_SYNC, 0x00, 0x07]);
+        dst.extend_from_slice(&item.timestamp.to_be_bytes());
+        dst.extend_from_slice(&item.current_time_seconds.to_be_bytes());
+        dst.extend_from_slice(&item.current_time_fraction.to_be_bytes());
+        dst.extend_from_slice(&item.next_timestamp.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+// ntp timestamps, 32.32 fixed point seconds since 1900
+pub struct RtpTimingPacket {
+    pub payload_type: u8,
+    pub reference_time: u64,
+    pub receive_time: u64,
+    pub send_time: u64,
+}
+
+// timing packets have no ssrc, times follow 8 bytes of header
+pub struct RtpTimingCodec {}
+
+impl Decoder for RtpTimingCodec {
+    type Item = RtpTimingPacket;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let src = src.split();
+        if src.len() < 32 {
+            return Err(Error::Rtp("Truncated timing packet".into()));
+        }
+        let time = |offset: usize| u64::from_be_bytes(src[offset..offset + 8].try_into().unwrap());
+
+        Ok(Some(RtpTimingPacket {
+            payload_type: src[1] & 0x7f,
+            reference_time: time(8),
+            receive_time: time(16),
+            send_time: time(24),
+        }))
+    }
+}
+
+impl Encoder<RtpTimingPacket> for RtpTimingCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: RtpTimingPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&[0x80, 0x80 | item.payload_type, 0x00, 0x07, 0, 0, 0, 0]);
+        dst.extend_from_slice(&item.reference_time.to_be_bytes());
+        dst.extend_from_slice(&item.receive_time.to_be_bytes());
+        dst.extend_from_slice(&item.send_time.to_be_bytes());
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -98,11 +184,75 @@ mod test {
 
         let req = codec.decode(&mut bytes)?.unwrap();
 
+        assert!(req.first);
         assert_eq!(req.timestamp, 1992580244);
         assert_eq!(req.current_time_seconds, 2209140244);
         assert_eq!(req.current_time_fraction, 1476154131);
         assert_eq!(req.next_timestamp, 1992657419);
 
+        // sequence number differs from the captured packet, senders use 7
+        let mut encoded = BytesMut::new();
+        codec.encode(req, &mut encoded)?;
+        assert_eq!(&encoded[..2], &data[..2]);
+        assert_eq!(&encoded[4..], &data[4..]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_packet() -> Result<()> {
+        let packet = RtpPacket {
+            payload_type: 96,
+            marker: true,
+            sequence: 1000,
+            timestamp: 352,
+            payload: vec![1, 2, 3],
+        };
+
+        let mut bytes = BytesMut::new();
+        RtpCodec {}.encode(packet, &mut bytes)?;
+        assert_eq!(&bytes[..], &[0x80, 0xe0, 0x03, 0xe8, 0, 0, 0x01, 0x60, 0, 0, 0, 0, 1, 2, 3]);
+
+        let packet = RtpCodec {}.decode(&mut bytes)?.unwrap();
+        assert_eq!(
+            (packet.payload_type, packet.marker, packet.sequence, packet.timestamp),
+            (96, true, 1000, 352)
+        );
+        assert_eq!(packet.payload, [1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timing() -> Result<()> {
+        let mut bytes = BytesMut::new();
+        let packet = RtpTimingPacket {
+            payload_type: PAYLOAD_TYPE_TIMING_REQUEST,
+            reference_time: 0,
+            receive_time: 0,
+            send_time: 0x0102030405060708,
+        };
+        RtpTimingCodec {}.encode(packet, &mut bytes)?;
+        assert_eq!(&bytes[..8], &[0x80, 0xd2, 0x00, 0x07, 0, 0, 0, 0]);
+
+        let packet = RtpTimingCodec {}.decode(&mut bytes)?.unwrap();
+        assert_eq!(packet.payload_type, PAYLOAD_TYPE_TIMING_REQUEST);
+        assert_eq!(packet.send_time, 0x0102030405060708);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid() -> Result<()> {
+        // invalid packet is consumed, so udp framing can go on with next datagram
+        let mut bytes = BytesMut::from(&[0x40u8, 0x60, 0x00][..]);
+        assert!(RtpCodec {}.decode(&mut bytes).is_err());
+        assert!(bytes.is_empty());
+
+        let mut bytes = BytesMut::from(&[0x90u8, 0xd4, 0x00, 0x04][..]);
+        assert!(RtpControlCodec {}.decode(&mut bytes).is_err());
+        assert!(bytes.is_empty());
+
         Ok(())
     }
 }
@@ -1,72 +1,152 @@
 mod request;
 mod response;
 
-use std::collections::HashMap;
 use std::str;
 
-use anyhow::Result;
 use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-pub use request::RtspRequest;
-pub use response::{RtspResponse, RtspStatusCode};
+use crate::error::{Error, Result};
+
+pub use request::{RtspHeaders, RtspRequest};
+pub use response::{RtspError, RtspResponse, RtspStatusCode};
+
+pub enum RtspMessage {
+    Request(RtspRequest),
+    // rtp or control packet sent on rtsp connection with RTP/AVP/TCP interleaved transport
+    Interleaved { channel: u8, data: Vec<u8> },
+    // answer to our request when we're the sender
+    Response { status: u16, headers: RtspHeaders, content: Vec<u8> },
+    // unparseable or oversized request, answered with 400 before the connection is closed
+    Malformed(Error),
+}
+
+// bounds what a peer can make us buffer
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+// large enough for cover art sent with SET_PARAMETER
+const MAX_CONTENT_LENGTH: usize = 4 * 1024 * 1024;
 
 pub struct RtspCodec {}
 
 impl Decoder for RtspCodec {
-    type Item = RtspRequest;
-    type Error = anyhow::Error;
+    type Item = RtspMessage;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let header_end = src.windows(4).position(|window| window == b"\r\n\r\n");
-        if header_end.is_none() {
-            return Ok(None); //partial
-        }
-        let header_end = header_end.unwrap() + 4;
-        let header = str::from_utf8(&src[..header_end - 4])?;
+        // rfc2326 10.12, `$`, channel, 2 bytes length and data
+        if src.first() == Some(&b'$') {
+            if src.len() < 4 {
+                return Ok(None); //partial
+            }
+            let length = u16::from_be_bytes([src[2], src[3]]) as usize;
+            if src.len() < 4 + length {
+                return Ok(None); //partial
+            }
 
-        let lines = header.split("\r\n").collect::<Vec<_>>();
-        let (method, path, _) = {
-            let split = lines[0].split(' ').collect::<Vec<_>>();
+            let channel = src[1];
+            let data = src[4..4 + length].to_vec();
+            src.advance(4 + length);
 
-            (split[0].into(), split[1].into(), split[2])
+            return Ok(Some(RtspMessage::Interleaved { channel, data }));
+        }
+
+        let header_end = match src.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(position) => position + 4,
+            None if src.len() > MAX_HEADER_SIZE => return Ok(Some(malformed(src, Error::Rtsp("Header too large".into())))),
+            None => return Ok(None), //partial
         };
+        if header_end > MAX_HEADER_SIZE {
+            return Ok(Some(malformed(src, Error::Rtsp("Header too large".into()))));
+        }
 
-        let mut headers = HashMap::new();
-        for header_line in lines.into_iter().skip(1) {
-            let split = header_line.split(':').collect::<Vec<_>>();
+        let (start_line, headers, length) = match parse_header(&src[..header_end - 4]) {
+            Ok(header) => header,
+            Err(err) => return Ok(Some(malformed(src, err))),
+        };
+        if length > MAX_CONTENT_LENGTH {
+            return Ok(Some(malformed(src, Error::Rtsp(format!("Content-Length {length} too large")))));
+        }
 
-            let (key, value) = (split[0].trim().to_owned(), split[1].trim().to_owned());
+        if src.len() < header_end + length {
+            src.reserve(header_end + length - src.len());
 
-            headers.insert(key, value);
+            return Ok(None); //partial
         }
 
-        // TODO header casing
-        let content = if let Some(length) = headers.get("Content-Length") {
-            let length = length.parse::<usize>()?;
+        let content = src[header_end..header_end + length].to_vec();
+        src.advance(header_end + length);
+
+        Ok(Some(match start_line {
+            StartLine::Request { method, path } => RtspMessage::Request(RtspRequest {
+                method,
+                path,
+                headers,
+                content,
+            }),
+            StartLine::Response { status } => RtspMessage::Response { status, headers, content },
+        }))
+    }
+}
 
-            if src.len() < header_end + length {
-                return Ok(None); //partial
-            }
+enum StartLine {
+    Request { method: String, path: String },
+    Response { status: u16 },
+}
 
-            src[header_end..header_end + length].to_vec()
-        } else {
-            Vec::new()
-        };
+// framing can't be trusted after malformed request, so rest of the buffer is dropped
+fn malformed(src: &mut BytesMut, err: Error) -> RtspMessage {
+    src.clear();
 
-        src.advance(header_end + content.len());
+    RtspMessage::Malformed(err)
+}
 
-        Ok(Some(RtspRequest {
-            method,
-            path,
-            headers,
-            content,
-        }))
+// returns request or status line, headers and content length
+fn parse_header(header: &[u8]) -> Result<(StartLine, RtspHeaders, usize)> {
+    let header = str::from_utf8(header).map_err(|_| Error::Rtsp("Header is not utf-8".into()))?;
+    let mut lines = header.split("\r\n");
+
+    let start_line = lines.next().unwrap_or_default();
+    let start_line = match start_line.split(' ').collect::<Vec<_>>()[..] {
+        // reason phrase may contain spaces
+        [version, status, ..] if version.starts_with("RTSP/") => StartLine::Response {
+            status: status.parse().map_err(|_| Error::Rtsp(format!("Invalid status line {start_line:?}")))?,
+        },
+        [method, path, version] if !method.is_empty() && !path.is_empty() && (version.starts_with("RTSP/") || version.starts_with("HTTP/")) => {
+            StartLine::Request {
+                method: method.into(),
+                path: path.into(),
+            }
+        }
+        _ => return Err(Error::Rtsp(format!("Invalid request line {start_line:?}"))),
+    };
+
+    let mut headers = RtspHeaders::new();
+    for line in lines {
+        // values may contain colons, i.e. urls in Transport
+        let (name, value) = line.split_once(':').ok_or_else(|| Error::Rtsp(format!("Invalid header line {line:?}")))?;
+        if name.is_empty() || name.contains(|x: char| x.is_ascii_whitespace() || x.is_ascii_control()) {
+            return Err(Error::Rtsp(format!("Invalid header name {name:?}")));
+        }
+
+        headers.append(name, value.trim());
     }
+
+    let length = match headers.get("Content-Length") {
+        Some(length) => {
+            if headers.get_all("Content-Length").any(|x| x != length) {
+                return Err(Error::Rtsp("Conflicting Content-Length".into()));
+            }
+
+            length.parse().map_err(|_| Error::Rtsp(format!("Invalid Content-Length {length:?}")))?
+        }
+        None => 0,
+    };
+
+    Ok((start_line, headers, length))
 }
 
 impl Encoder<RtspResponse> for RtspCodec {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn encode(&mut self, item: RtspResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
         dst.extend(format!("RTSP/1.0 {} {}\r\n", item.status as usize, item.status.as_string()).as_bytes());
@@ -76,7 +156,30 @@ impl Encoder<RtspResponse> for RtspCodec {
 
             dst.extend(header_line.as_bytes());
         }
+        if !item.content.is_empty() {
+            dst.extend(format!("Content-Length: {}\r\n", item.content.len()).as_bytes());
+        }
+        dst.extend("\r\n".as_bytes());
+        dst.extend(&item.content);
+
+        Ok(())
+    }
+}
+
+impl Encoder<RtspRequest> for RtspCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: RtspRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend(format!("{} {} RTSP/1.0\r\n", item.method, item.path).as_bytes());
+
+        for (key, value) in item.headers.iter() {
+            dst.extend(format!("{key}: {value}\r\n").as_bytes());
+        }
+        if !item.content.is_empty() {
+            dst.extend(format!("Content-Length: {}\r\n", item.content.len()).as_bytes());
+        }
         dst.extend("\r\n".as_bytes());
+        dst.extend(&item.content);
 
         Ok(())
     }
@@ -96,7 +199,10 @@ mod test {
         let mut codec = RtspCodec {};
         let mut bytes = BytesMut::from(data);
 
-        let req = codec.decode(&mut bytes)?.unwrap();
+        let req = match codec.decode(&mut bytes)?.unwrap() {
+            RtspMessage::Request(req) => req,
+            _ => panic!("Expected request"),
+        };
 
         assert_eq!(req.method, "GET");
         assert_eq!(req.path, "/info");
@@ -105,6 +211,62 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_headers() -> Result<()> {
+        let data = "SETUP rtsp://10.0.0.1/1 RTSP/1.0\r\ncseq: 3\r\nTransport: RTP/AVP/UDP;url=rtsp://10.0.0.1:5000\r\nX-Test: 1\r\nx-test: 2\r\n\
+                    CONTENT-LENGTH: 4\r\n\r\nabcd";
+
+        let mut codec = RtspCodec {};
+        let mut bytes = BytesMut::from(data);
+
+        let req = match codec.decode(&mut bytes)?.unwrap() {
+            RtspMessage::Request(req) => req,
+            _ => panic!("Expected request"),
+        };
+
+        assert_eq!(req.headers.get("CSeq"), Some("3"));
+        assert_eq!(req.headers.get("Transport"), Some("RTP/AVP/UDP;url=rtsp://10.0.0.1:5000"));
+        assert_eq!(req.headers.get_all("X-Test").collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(req.content, b"abcd");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed() -> Result<()> {
+        let mut codec = RtspCodec {};
+        let mut decode = |data: &[u8]| match codec.decode(&mut BytesMut::from(data)) {
+            Ok(Some(RtspMessage::Malformed(Error::Rtsp(reason)))) => reason,
+            _ => panic!("Expected malformed request"),
+        };
+
+        assert!(decode(b"OPTIONS\r\n\r\n").starts_with("Invalid request line"));
+        assert!(decode(b"OPTIONS * RTSP/1.0\r\nCSeq 1\r\n\r\n").starts_with("Invalid header line"));
+        assert!(decode(b"OPTIONS * RTSP/1.0\r\nContent-Length: -1\r\n\r\n").starts_with("Invalid Content-Length"));
+        assert!(decode(b"OPTIONS * RTSP/1.0\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n").starts_with("Conflicting"));
+        assert!(decode(b"OPTIONS * RTSP/1.0\r\nContent-Length: 1000000000\r\n\r\n").ends_with("too large"));
+        assert_eq!(decode(&[b'A'; MAX_HEADER_SIZE + 1]), "Header too large");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interleaved() -> Result<()> {
+        let mut codec = RtspCodec {};
+        let mut bytes = BytesMut::from(&b"$\x01\x00\x03abcGET_PARAMETER rtsp://x RTSP/1.0\r\nCSeq: 1\r\n\r\n$\x00\x00"[..]);
+
+        match codec.decode(&mut bytes)?.unwrap() {
+            RtspMessage::Interleaved { channel, data } => assert_eq!((channel, data.as_slice()), (1, &b"abc"[..])),
+            _ => panic!("Expected interleaved data"),
+        }
+        assert!(matches!(codec.decode(&mut bytes)?, Some(RtspMessage::Request(_))));
+        // partial frame
+        assert!(codec.decode(&mut bytes)?.is_none());
+        assert_eq!(bytes.len(), 3);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_simple_response() -> Result<()> {
         let response = RtspResponse::with_headers(RtspStatusCode::Ok, hashmap! { "Test" => "Test".into() });
@@ -119,4 +281,77 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_response_content() -> Result<()> {
+        let response = RtspResponse::with_content(RtspStatusCode::NotEnoughBandwidth, "text/plain", "Test".into());
+
+        let mut codec = RtspCodec {};
+        let mut bytes = BytesMut::new();
+
+        codec.encode(response, &mut bytes)?;
+
+        let response_text = str::from_utf8(&bytes)?;
+        assert_eq!(
+            response_text,
+            "RTSP/1.0 453 Not Enough Bandwidth\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nTest"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_response() -> Result<()> {
+        let mut headers = RtspHeaders::new();
+        headers.append("CSeq", "2");
+        let request = RtspRequest {
+            method: "SET_PARAMETER".into(),
+            path: "rtsp://10.0.0.1/1".into(),
+            headers,
+            content: b"volume: -15.0\r\n".to_vec(),
+        };
+
+        let mut codec = RtspCodec {};
+        let mut bytes = BytesMut::new();
+        codec.encode(request, &mut bytes)?;
+        assert_eq!(
+            str::from_utf8(&bytes)?,
+            "SET_PARAMETER rtsp://10.0.0.1/1 RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 15\r\n\r\nvolume: -15.0\r\n"
+        );
+
+        let mut bytes = BytesMut::from("RTSP/1.0 453 Not Enough Bandwidth\r\nCSeq: 2\r\nContent-Length: 4\r\n\r\nBusy");
+        match codec.decode(&mut bytes)?.unwrap() {
+            RtspMessage::Response { status, headers, content } => {
+                assert_eq!(status, 453);
+                assert_eq!(headers.get("CSeq"), Some("2"));
+                assert_eq!(content, b"Busy");
+            }
+            _ => panic!("Expected response"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_response() -> Result<()> {
+        let error = anyhow::Error::from(RtspError::new(RtspStatusCode::MethodNotValidInThisState, "RECORD before SETUP"));
+        let response = RtspResponse::from(&error);
+        assert_eq!(response.status, RtspStatusCode::MethodNotValidInThisState);
+        assert_eq!(response.content, b"RECORD before SETUP");
+
+        let response = RtspResponse::from(&anyhow::Error::from(Error::Sdp("Missing rtpmap".into())));
+        assert_eq!(response.status, RtspStatusCode::BadRequest);
+        assert_eq!(response.content, b"Invalid SDP: Missing rtpmap");
+
+        // internal errors aren't exposed
+        let response = RtspResponse::from(&anyhow::anyhow!("Secret"));
+        assert_eq!(response.status, RtspStatusCode::InternalServerError);
+        assert!(response.content.is_empty());
+
+        let response = RtspResponse::from(&anyhow::Error::from(Error::Sink("Secret".into())));
+        assert_eq!(response.status, RtspStatusCode::InternalServerError);
+        assert!(response.content.is_empty());
+
+        Ok(())
+    }
 }
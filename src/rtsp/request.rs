@@ -1,8 +1,47 @@
-use std::collections::HashMap;
+use std::fmt;
+
+// header names are case insensitive, and a header may appear more than once
+#[derive(Default, Clone)]
+pub struct RtspHeaders(Vec<(String, String)>);
+
+impl RtspHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    // first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl fmt::Debug for RtspHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
 
 pub struct RtspRequest {
     pub method: String,
     pub path: String,
-    pub headers: HashMap<String, String>,
+    pub headers: RtspHeaders,
     pub content: Vec<u8>,
 }
@@ -1,12 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, error, fmt};
 
-#[derive(Clone, Copy)]
+use crate::error::Error;
+
+// rfc2326 7.1.1, with the codes airplay senders act on. not all are sent yet, i.e. 401 needs password support
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RtspStatusCode {
     Ok = 200,
     BadRequest = 400,
+    #[allow(dead_code)]
+    Unauthorized = 401,
+    Forbidden = 403,
     NotFound = 404,
+    #[allow(dead_code)]
     MethodNotAllowed = 405,
+    NotEnoughBandwidth = 453,
+    SessionNotFound = 454,
+    MethodNotValidInThisState = 455,
+    UnsupportedTransport = 461,
     InternalServerError = 500,
+    NotImplemented = 501,
 }
 
 impl RtspStatusCode {
@@ -14,9 +26,16 @@ impl RtspStatusCode {
         match self {
             RtspStatusCode::Ok => "OK",
             RtspStatusCode::BadRequest => "Bad Request",
+            RtspStatusCode::Unauthorized => "Unauthorized",
+            RtspStatusCode::Forbidden => "Forbidden",
             RtspStatusCode::NotFound => "Not Found",
             RtspStatusCode::MethodNotAllowed => "Method Not Allowed",
+            RtspStatusCode::NotEnoughBandwidth => "Not Enough Bandwidth",
+            RtspStatusCode::SessionNotFound => "Session Not Found",
+            RtspStatusCode::MethodNotValidInThisState => "Method Not Valid in This State",
+            RtspStatusCode::UnsupportedTransport => "Unsupported Transport",
             RtspStatusCode::InternalServerError => "Internal Server Error",
+            RtspStatusCode::NotImplemented => "Not Implemented",
         }
     }
 }
@@ -24,6 +43,7 @@ impl RtspStatusCode {
 pub struct RtspResponse {
     pub status: RtspStatusCode,
     pub headers: HashMap<&'static str, String>,
+    pub content: Vec<u8>,
 }
 
 impl RtspResponse {
@@ -31,10 +51,60 @@ impl RtspResponse {
         Self {
             status,
             headers: HashMap::new(),
+            content: Vec::new(),
         }
     }
 
     pub fn with_headers(status: RtspStatusCode, headers: HashMap<&'static str, String>) -> Self {
-        Self { status, headers }
+        Self {
+            status,
+            headers,
+            content: Vec::new(),
+        }
+    }
+
+    pub fn with_content(status: RtspStatusCode, content_type: &str, content: Vec<u8>) -> Self {
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type", content_type.into());
+
+        Self { status, headers, content }
+    }
+}
+
+// handler error answered with its status and reason, protocol errors become 400 and other errors 500
+#[derive(Debug)]
+pub struct RtspError {
+    pub status: RtspStatusCode,
+    pub reason: String,
+}
+
+impl RtspError {
+    pub fn new(status: RtspStatusCode, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for RtspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status as u16, self.status.as_string(), self.reason)
+    }
+}
+
+impl error::Error for RtspError {}
+
+impl From<&anyhow::Error> for RtspResponse {
+    fn from(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<RtspError>() {
+            return Self::with_content(err.status, "text/plain", err.reason.clone().into());
+        }
+
+        match err.downcast_ref::<Error>() {
+            Some(err) if err.is_protocol() => Self::with_content(RtspStatusCode::BadRequest, "text/plain", err.to_string().into()),
+            // internal details aren't sent to the peer
+            _ => Self::new(RtspStatusCode::InternalServerError),
+        }
     }
 }
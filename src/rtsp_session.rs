@@ -1,73 +1,276 @@
-use std::{collections::HashMap, io, rc::Rc, str};
+use std::{collections::HashMap, io, net::SocketAddr, str, sync::Arc};
 
 use anyhow::{anyhow, Result};
-use futures::{select, SinkExt, StreamExt};
+use bytes::BytesMut;
+use futures::{future, select, FutureExt, SinkExt, Stream, StreamExt};
 use log::{debug, trace, warn};
-use mac_address::MacAddress;
 use maplit::hashmap;
 use sdp::SessionDescription;
-use tokio::net::{TcpStream, UdpSocket};
-use tokio_util::{codec::Framed, udp::UdpFramed};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, watch, Notify},
+    task::{spawn, JoinHandle},
+    time::{sleep, Instant},
+};
+use tokio_util::{
+    codec::{Decoder as _, Framed, LengthDelimitedCodec},
+    udp::UdpFramed,
+};
 
 use super::{
     cipher::{AppleChallenge, RsaAesCipher},
-    decoder::{AppleLoselessDecoder, Decoder, RawPCMDecoder},
-    rtp::{RtpCodec, RtpControlCodec, RtpControlPacket, RtpPacket},
-    rtsp::{RtspCodec, RtspRequest, RtspResponse, RtspStatusCode},
-    sink::{AudioFormat, AudioSinkSession},
+    decoder::{AppleLoselessDecoder, CodecInfo, Decoder, RawPCMDecoder},
+    dmap,
+    error::Error,
+    event::{Event, EventKind, Metadata, SessionEndReason},
+    hook::HookEvent,
+    receiver::ReceiverContext,
+    rtp::{RtpCodec, RtpControlCodec, RtpControlPacket, RtpPacket, PAYLOAD_TYPE_RETRANSMIT, PAYLOAD_TYPE_SYNC},
+    rtsp::{RtspCodec, RtspError, RtspMessage, RtspRequest, RtspResponse, RtspStatusCode},
+    sessions::SessionInfo,
+    sink::{AudioFormat, AudioSink, GainStage},
 };
 
 struct StreamInfo {
     rtp_type: u8,
     decoder: Box<dyn Decoder>,
     cipher: Option<RsaAesCipher>,
-    session: Rc<dyn AudioSinkSession>,
+}
+
+// packets waiting for decode, about half a second of 352 frame packets
+const AUDIO_QUEUE: usize = 64;
+
+// for the audio task, in order with packets
+enum AudioCommand {
+    Packet(Arc<StreamInfo>, RtpPacket),
+    FadeOut,
+    Stop,
+}
+
+// started at first RECORD, so that refused or idle connections don't open a sink stream
+struct AudioOutput {
+    output: Arc<GainStage>,
+    // decrypts, decodes and writes to output off the network task
+    commands: mpsc::Sender<AudioCommand>,
+    task: JoinHandle<Result<()>>,
+}
+
+// bound at SETUP, rtsp_loop takes them over
+struct UdpSockets {
+    rtp: UdpSocket,
+    control: UdpSocket,
+    timing: UdpSocket,
+}
+
+// rfc2326 A.2 server states, with airplay's ANNOUNCE in front of SETUP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionState {
+    Init,
+    Announced,
+    Ready,
+    Playing,
+    Paused,
+    TornDown,
+}
+
+impl SessionState {
+    // state after successful request, None for methods allowed in any state
+    fn next(self, method: &str) -> Result<Option<Self>, RtspError> {
+        use SessionState::*;
+
+        let next = match (method, self) {
+            ("ANNOUNCE", Init | Announced | TornDown) => Announced,
+            // repeated SETUP replaces transport
+            ("SETUP", Announced | Ready) => Ready,
+            ("RECORD", Ready | Playing | Paused) => Playing,
+            ("PAUSE", Playing | Paused) => Paused,
+            ("FLUSH", Ready | Playing | Paused) => self,
+            ("TEARDOWN", Announced | Ready | Playing | Paused) => TornDown,
+            ("ANNOUNCE" | "SETUP" | "RECORD" | "PAUSE" | "FLUSH" | "TEARDOWN", _) => {
+                return Err(RtspError::new(
+                    RtspStatusCode::MethodNotValidInThisState,
+                    format!("{method} in {self:?} state"),
+                ))
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(next))
+    }
+
+    // session id is issued at SETUP
+    fn has_session(self) -> bool {
+        matches!(self, SessionState::Ready | SessionState::Playing | SessionState::Paused)
+    }
 }
 
 pub struct RtspSession {
     id: u32,
-    rtp_port: u16,
-    control_port: u16,
-    timing_port: u16,
+    state: SessionState,
+    context: Arc<ReceiverContext>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    connected: bool,
+    client_name: Option<String>,
+    metadata: Metadata,
+    started: bool,
+    stop: Arc<Notify>,
+    end_reason: Option<SessionEndReason>,
+    // ended at TEARDOWN, until the sender announces again on the same connection
+    ended: bool,
+    // ports taken from the pool at SETUP
+    ports: Vec<u16>,
+    udp_sockets: Option<UdpSockets>,
+    // RTP/AVP/TCP with separate data connection
+    data_listener: Option<TcpListener>,
+    // RTP/AVP/TCP interleaved rtp and control channels
+    interleaved: Option<(u8, u8)>,
     apple_challenge: AppleChallenge,
-    session: Rc<dyn AudioSinkSession>,
-    stream_info: Option<StreamInfo>,
+    sink: Arc<dyn AudioSink>,
+    // sender tells its volume after RECORD, until then we play at max
+    volume: f32,
+    audio: Option<AudioOutput>,
+    stream_info: Option<Arc<StreamInfo>>,
 }
 
 impl RtspSession {
-    pub async fn start(id: u32, rtsp: TcpStream, session: Rc<dyn AudioSinkSession>, mac_address: MacAddress) -> Result<()> {
-        let rtp = UdpSocket::bind("0.0.0.0:0").await?;
-        let control = UdpSocket::bind("0.0.0.0:0").await?;
-        let timing = UdpSocket::bind("0.0.0.0:0").await?;
+    pub async fn start(id: u32, rtsp: TcpStream, sink: Arc<dyn AudioSink>, context: Arc<ReceiverContext>) -> Result<()> {
+        let volume = context.volume_curve.gain(0.0);
+        let apple_challenge = AppleChallenge::new(context.key.clone(), rtsp.local_addr()?.ip(), &context.mac_address.bytes());
 
         let mut session = Self {
             id,
-            rtp_port: rtp.local_addr()?.port(),
-            control_port: control.local_addr()?.port(),
-            timing_port: timing.local_addr()?.port(),
-            apple_challenge: AppleChallenge::new(rtsp.local_addr()?.ip(), &mac_address.bytes()),
-            session,
+            state: SessionState::Init,
+            context,
+            local_addr: rtsp.local_addr()?,
+            peer_addr: rtsp.peer_addr()?,
+            connected: false,
+            client_name: None,
+            metadata: Metadata::default(),
+            started: false,
+            stop: Arc::new(Notify::new()),
+            end_reason: None,
+            ended: false,
+            ports: Vec::new(),
+            udp_sockets: None,
+            data_listener: None,
+            interleaved: None,
+            apple_challenge,
+            sink,
+            volume,
+            audio: None,
             stream_info: None,
         };
 
-        session.rtsp_loop(rtsp, rtp, control, timing).await
+        let result = session.rtsp_loop(rtsp).await;
+        // failed output is why the loop stopped, if it did
+        let result = session.stop_audio().await.and(result);
+        session.context.sessions.release(id);
+        session.release_ports();
+
+        if !session.ended {
+            let reason = match &result {
+                Ok(_) => session.end_reason.take().unwrap_or(SessionEndReason::Disconnected),
+                Err(err) => SessionEndReason::Error(err.to_string()),
+            };
+            session.end(reason).await;
+        }
+
+        result
+    }
+
+    fn hook_env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("RAS_SESSION_ID", self.id.to_string()),
+            ("RAS_CLIENT_IP", self.peer_addr.ip().to_string()),
+        ];
+
+        let optional = [
+            ("RAS_CLIENT_NAME", &self.client_name),
+            ("RAS_TITLE", &self.metadata.title),
+            ("RAS_ARTIST", &self.metadata.artist),
+            ("RAS_ALBUM", &self.metadata.album),
+            ("RAS_GENRE", &self.metadata.genre),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                env.push((key, value.clone()));
+            }
+        }
+
+        env
+    }
+
+    // at TEARDOWN or when the connection ends, whichever comes first
+    async fn end(&mut self, reason: SessionEndReason) {
+        self.ended = true;
+        if self.started {
+            self.started = false;
+
+            let mut env = self.hook_env();
+            env.push(("RAS_END_REASON", reason.to_string()));
+            self.context.hooks.run(HookEvent::SessionEnd, env).await;
+        }
+        self.emit(EventKind::SessionEnded { reason });
     }
 
-    async fn rtsp_loop(&mut self, rtsp: TcpStream, rtp: UdpSocket, control: UdpSocket, timing: UdpSocket) -> Result<()> {
+    fn emit(&self, kind: EventKind) {
+        // it's fine to have no subscribers
+        let _ = self.context.events.send(Event { session_id: self.id, kind });
+    }
+
+    fn release_ports(&mut self) {
+        self.context.ports.release(&self.ports);
+        self.ports.clear();
+        self.udp_sockets = None;
+        self.data_listener = None;
+        self.interleaved = None;
+    }
+
+    async fn rtsp_loop(&mut self, rtsp: TcpStream) -> Result<()> {
         let (mut rtsp_write, rtsp_read) = Framed::new(rtsp, RtspCodec {}).split();
-        let mut rtp = UdpFramed::new(rtp, RtpCodec {}).fuse();
-        let mut control = UdpFramed::new(control, RtpControlCodec {}).fuse();
-        let mut timing = UdpFramed::new(timing, RtpCodec {}).fuse();
+        let mut rtp = None;
+        let mut control = None;
+        let mut timing = None;
+        let mut data_listener = None;
+        let mut data = None;
+
+        // timer is rearmed lazily, so that packets don't have to reset it. only the sender's valid packets count
+        let idle_timeout = self.context.idle_timeout;
+        let mut last_activity = Instant::now();
+        let idle = sleep(idle_timeout);
+        tokio::pin!(idle);
+        let mut shutdown = self.context.shutdown.subscribe();
 
         let mut rtsp_read = rtsp_read.fuse();
         loop {
             select! {
                 rtsp_packet = rtsp_read.next() => {
+                    last_activity = Instant::now();
                     if rtsp_packet.is_none() {
                         // connection closed
                         return Ok(())
                     }
-                    let req = rtsp_packet.unwrap()?;
+                    let req = match rtsp_packet.unwrap()? {
+                        RtspMessage::Request(req) => req,
+                        RtspMessage::Interleaved { channel, data } => {
+                            self.handle_interleaved(channel, &data).await?;
+
+                            continue;
+                        }
+                        RtspMessage::Response { status, .. } => {
+                            warn!("Unexpected response {} from {}", status, self.peer_addr);
+                            rtsp_write.send(RtspResponse::new(RtspStatusCode::BadRequest)).await?;
+
+                            return Ok(());
+                        }
+                        RtspMessage::Malformed(reason) => {
+                            warn!("Malformed request from {}: {}", self.peer_addr, reason);
+                            rtsp_write.send(RtspResponse::new(RtspStatusCode::BadRequest)).await?;
+
+                            return Ok(());
+                        }
+                    };
                     trace!(
                         "req {} {} {:?} {:?}",
                         req.method,
@@ -80,10 +283,91 @@ impl RtspSession {
                     trace!("res {} {:?}", res.status as u32, res.headers);
 
                     rtsp_write.send(res).await?;
+
+                    if let Some(sockets) = self.udp_sockets.take() {
+                        rtp = Some(UdpFramed::new(sockets.rtp, RtpCodec {}));
+                        control = Some(UdpFramed::new(sockets.control, RtpControlCodec {}));
+                        timing = Some(UdpFramed::new(sockets.timing, RtpCodec {}));
+                    }
+                    if let Some(listener) = self.data_listener.take() {
+                        data_listener = Some(listener);
+                    }
+                    // ports go back to the pool even if sender keeps the connection
+                    if req.method == "TEARDOWN" {
+                        (rtp, control, timing, data_listener, data) = (None, None, None, None, None);
+                        self.release_ports();
+                    }
+                }
+                stream = accept(&mut data_listener).fuse() => {
+                    let (stream, addr) = stream?;
+                    // anyone reaching the port could inject audio otherwise, keep listening for the sender
+                    if addr.ip() != self.peer_addr.ip() {
+                        warn!("Refusing data connection from {}, session is from {}", addr, self.peer_addr.ip());
+
+                        continue;
+                    }
+                    debug!("Data connection from {}", addr);
+
+                    // rfc4571, packets are prefixed with 2 bytes length
+                    data = Some(LengthDelimitedCodec::builder().length_field_length(2).new_framed(stream));
+                    data_listener = None;
+                }
+                packet = next_packet(&mut data).fuse() => match packet {
+                    Some(Ok(packet)) => {
+                        last_activity = Instant::now();
+                        self.handle_rtp_frame(&packet).await?
+                    }
+                    // sender may reconnect with another SETUP
+                    Some(Err(err)) => {
+                        debug!("Data connection failed: {:?}", err);
+                        data = None;
+                    }
+                    None => {
+                        debug!("Data connection closed");
+                        data = None;
+                    }
+                },
+                rtp_packet = next_packet(&mut rtp).fuse() => {
+                    if let Some(packet) = self.peer_datagram(datagram(rtp_packet)?) {
+                        last_activity = Instant::now();
+                        self.handle_rtp(packet).await?
+                    }
+                },
+                control_packet = next_packet(&mut control).fuse() => {
+                    if let Some(packet) = self.peer_datagram(datagram(control_packet)?) {
+                        last_activity = Instant::now();
+                        self.handle_control(packet).await?
+                    }
+                },
+                timing_packet = next_packet(&mut timing).fuse() => {
+                    if let Some(packet) = self.peer_datagram(datagram(timing_packet)?) {
+                        last_activity = Instant::now();
+                        self.handle_timing(packet).await?
+                    }
+                },
+                _ = idle.as_mut().fuse() => {
+                    let deadline = last_activity + idle_timeout;
+                    if deadline > Instant::now() {
+                        idle.as_mut().reset(deadline);
+
+                        continue;
+                    }
+
+                    debug!("Session {} from {} idle for {:?}", self.id, self.peer_addr, idle_timeout);
+                    self.end_reason = Some(SessionEndReason::Timeout);
+
+                    return Ok(())
+                }
+                _ = self.stop.notified().fuse() => {
+                    self.end_reason = Some(SessionEndReason::Preempted);
+
+                    return Ok(())
+                }
+                _ = shutting_down(&mut shutdown).fuse() => {
+                    self.end_reason = Some(SessionEndReason::Shutdown);
+
+                    return Ok(())
                 }
-                rtp_packet = rtp.next() => self.handle_rtp(rtp_packet.unwrap()?.0).await?,
-                control_packet = control.next() => self.handle_control(control_packet.unwrap()?.0).await?,
-                timing_packet = timing.next() => self.handle_timing(timing_packet.unwrap()?.0).await?,
             }
         }
     }
@@ -106,68 +390,221 @@ impl RtspSession {
         Ok(())
     }
 
+    async fn handle_interleaved(&self, channel: u8, data: &[u8]) -> Result<()> {
+        match self.interleaved {
+            Some((rtp, control)) if channel == rtp || channel == control => self.handle_rtp_frame(data).await,
+            _ => {
+                trace!("Ignoring interleaved data on channel {}", channel);
+
+                Ok(())
+            }
+        }
+    }
+
+    // audio, sync and retransmitted packets share single stream over tcp
+    async fn handle_rtp_frame(&self, data: &[u8]) -> Result<()> {
+        let payload_type = data.get(1).map(|x| x & 0x7f);
+        let mut data = BytesMut::from(data);
+
+        match payload_type {
+            Some(PAYLOAD_TYPE_SYNC) => {
+                if let Some(packet) = valid(RtpControlCodec {}.decode(&mut data)) {
+                    self.handle_control(packet).await?;
+                }
+            }
+            Some(PAYLOAD_TYPE_RETRANSMIT) => trace!("Ignoring retransmitted packet"),
+            _ => {
+                if let Some(packet) = valid(RtpCodec {}.decode(&mut data)) {
+                    self.handle_rtp(packet).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // anyone on the network can reach our udp ports, only the sender's packets are played
+    fn peer_datagram<T>(&self, datagram: Option<(T, SocketAddr)>) -> Option<T> {
+        match datagram {
+            Some((packet, addr)) if addr.ip() == self.peer_addr.ip() => Some(packet),
+            Some((_, addr)) => {
+                trace!("Dropping datagram from {}", addr);
+                None
+            }
+            None => None,
+        }
+    }
+
     async fn handle_rtp(&self, packet: RtpPacket) -> Result<()> {
-        let stream_info = self.stream_info.as_ref().ok_or_else(|| anyhow!("unexpected rtp packet"))?;
+        // i.e. late packets after TEARDOWN, or sender streaming before RECORD
+        if !matches!(self.state, SessionState::Playing | SessionState::Paused) {
+            trace!("Dropping rtp packet in {:?} state", self.state);
+
+            return Ok(());
+        }
+
+        let stream_info = self.stream_info.clone().ok_or_else(|| anyhow!("unexpected rtp packet"))?;
+
+        // waits while the audio task is behind, which holds up this session only
+        self.send_audio(AudioCommand::Packet(stream_info, packet)).await
+    }
+
+    // nothing to do before audio output started
+    async fn send_audio(&self, command: AudioCommand) -> Result<()> {
+        match &self.audio {
+            Some(audio) => audio.commands.send(command).await.map_err(|_| anyhow!("Audio output stopped")),
+            None => Ok(()),
+        }
+    }
+
+    fn start_audio(&mut self) -> Result<()> {
+        if self.audio.is_none() {
+            let output = Arc::new(GainStage::new(self.sink.start()?, self.volume));
+            let (commands, receiver) = mpsc::channel(AUDIO_QUEUE);
+            let task = spawn(Self::audio_loop(receiver, output.clone()));
+
+            self.audio = Some(AudioOutput { output, commands, task });
+        }
+
+        Ok(())
+    }
+
+    // plays what's queued, returns the error which stopped the audio task early
+    async fn stop_audio(&mut self) -> Result<()> {
+        match self.audio.take() {
+            Some(audio) => {
+                let _ = audio.commands.send(AudioCommand::Stop).await;
+
+                audio.task.await?
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn audio_loop(mut commands: mpsc::Receiver<AudioCommand>, output: Arc<GainStage>) -> Result<()> {
+        while let Some(command) = commands.recv().await {
+            match command {
+                AudioCommand::Packet(stream_info, packet) => {
+                    let payload = match Self::decode_rtp(&stream_info, &packet) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            debug!("Dropping invalid rtp packet: {:?}", err);
+
+                            continue;
+                        }
+                    };
+
+                    output.write(
+                        &payload,
+                        stream_info.decoder.channels(),
+                        stream_info.decoder.rate(),
+                        stream_info.decoder.format(),
+                    )?;
+                }
+                AudioCommand::FadeOut => output.fade_out()?,
+                AudioCommand::Stop => break,
+            }
+        }
+
+        // don't cut off what's held back for fade out
+        if let Err(err) = output.fade_out() {
+            debug!("Can't fade out: {:?}", err);
+        }
+
+        Ok(())
+    }
+
+    fn decode_rtp(stream_info: &StreamInfo, packet: &RtpPacket) -> crate::Result<Vec<u8>> {
         if packet.payload_type != stream_info.rtp_type {
-            return Err(anyhow!("Invalid rtp payload type"));
+            return Err(Error::Rtp(format!("Invalid payload type {}", packet.payload_type)));
         }
 
-        let payload = if let Some(cipher) = &stream_info.cipher {
+        if let Some(cipher) = &stream_info.cipher {
             let decrypted = cipher.decrypt(&packet.payload)?;
 
-            stream_info.decoder.decode(&decrypted)?
+            stream_info.decoder.decode(&decrypted)
         } else {
-            stream_info.decoder.decode(&packet.payload)?
+            stream_info.decoder.decode(&packet.payload)
+        }
+    }
+
+    async fn handle_rtsp(&mut self, request: &RtspRequest) -> RtspResponse {
+        if !self.connected {
+            self.connected = true;
+            self.client_name = request.headers.get("X-Apple-Client-Name").map(Into::into);
+            self.emit(EventKind::Connected {
+                addr: self.peer_addr,
+                user_agent: request.headers.get("User-Agent").map(Into::into),
+                client_name: self.client_name.clone(),
+            });
+        }
+
+        let mut response = match self.handle_method(request).await {
+            Ok(response) => response,
+            Err(err) => {
+                if err.is::<RtspError>() {
+                    debug!("{} {} refused: {}", request.method, request.path, err);
+                } else {
+                    warn!("{} {} failed: {:?}", request.method, request.path, err);
+                }
+
+                RtspResponse::from(&err)
+            }
         };
 
-        stream_info.session.write(
-            &payload,
-            stream_info.decoder.channels(),
-            stream_info.decoder.rate(),
-            stream_info.decoder.format(),
-        )?;
+        // on errors too, senders match responses to requests by CSeq
+        if let Some(cseq) = request.headers.get("CSeq") {
+            response.headers.insert("CSeq", cseq.into());
+        }
+        response.headers.insert("Server", "ras/0.1".into());
 
-        Ok(())
+        response
     }
 
-    async fn handle_rtsp(&mut self, request: &RtspRequest) -> RtspResponse {
-        let cseq = request.headers.get("CSeq");
-        let apple_challenge = request.headers.get("Apple-Challenge");
+    async fn handle_method(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
+        let apple_response = match request.headers.get("Apple-Challenge") {
+            Some(challenge) => Some(
+                self.apple_challenge
+                    .response(challenge)
+                    .map_err(|err| RtspError::new(RtspStatusCode::Forbidden, format!("Invalid Apple-Challenge: {err}")))?,
+            ),
+            None => None,
+        };
+
+        // session id we gave at SETUP, optionally followed by parameters like `;timeout=60`
+        if let Some(session) = request.headers.get("Session") {
+            let session = session.split(';').next().unwrap_or_default().trim();
+            if request.method != "OPTIONS" && (!self.state.has_session() || session != self.id.to_string()) {
+                return Err(RtspError::new(RtspStatusCode::SessionNotFound, format!("Unknown session {session:?}")).into());
+            }
+        }
+
+        let next_state = self.state.next(&request.method)?;
 
-        let result = match request.method.as_str() {
+        let mut response = match request.method.as_str() {
             "ANNOUNCE" => self.handle_announce(request).await,
             "SETUP" => self.handle_setup(request).await,
-            "RECORD" => Ok(RtspResponse::new(RtspStatusCode::Ok)),
-            "PAUSE" => Ok(RtspResponse::new(RtspStatusCode::Ok)),
-            "FLUSH" => Ok(RtspResponse::new(RtspStatusCode::Ok)),
-            "TEARDOWN" => Ok(RtspResponse::new(RtspStatusCode::Ok)),
+            "RECORD" => self.handle_record(request).await,
+            "PAUSE" => self.handle_pause(request).await,
+            "FLUSH" => self.handle_flush(request).await,
+            "TEARDOWN" => self.handle_teardown(request).await,
             "OPTIONS" => self.handle_options(request).await,
             "GET_PARAMETER" => Ok(RtspResponse::new(RtspStatusCode::Ok)),
             "SET_PARAMETER" => self.handle_set_parameter(request).await,
             "POST" => Ok(RtspResponse::new(RtspStatusCode::NotFound)),
             "GET" => Ok(RtspResponse::new(RtspStatusCode::NotFound)),
-            _ => {
-                warn!("Unhandled method {}", request.method);
-
-                Ok(RtspResponse::new(RtspStatusCode::MethodNotAllowed))
-            }
-        };
-
-        if let Ok(mut response) = result {
-            if let Some(cseq) = cseq {
-                response.headers.insert("CSeq", cseq.into());
-            }
-            if let Some(apple_challenge) = apple_challenge {
-                response
-                    .headers
-                    .insert("Apple-Response", self.apple_challenge.response(apple_challenge).unwrap());
-            }
-            response.headers.insert("Server", "ras/0.1".into());
+            method => Err(RtspError::new(RtspStatusCode::NotImplemented, format!("Unknown method {method}")).into()),
+        }?;
 
-            response
-        } else {
-            RtspResponse::new(RtspStatusCode::InternalServerError)
+        if let Some(apple_response) = apple_response {
+            response.headers.insert("Apple-Response", apple_response);
+        }
+        if let Some(next_state) = next_state.filter(|x| *x != self.state) {
+            debug!("Session {} {:?} -> {:?}", self.id, self.state, next_state);
+            self.state = next_state;
         }
+
+        Ok(response)
     }
 
     async fn handle_options(&mut self, _: &RtspRequest) -> Result<RtspResponse> {
@@ -179,132 +616,921 @@ impl RtspSession {
         ))
     }
 
-    async fn handle_set_parameter(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
-        let content_type = request.headers.get("Content-Type");
-        if content_type.is_none() {
-            return Ok(RtspResponse::new(RtspStatusCode::BadRequest));
+    async fn handle_record(&mut self, _: &RtspRequest) -> Result<RtspResponse> {
+        self.start_audio()?;
+
+        if !self.started {
+            self.started = true;
+            self.context.hooks.run(HookEvent::SessionStart, self.hook_env()).await;
         }
+        self.emit(EventKind::PlaybackStarted);
 
-        let content_type = content_type.unwrap();
+        let mut response = RtspResponse::new(RtspStatusCode::Ok);
+        if let Some(latency) = self.context.latency {
+            response.headers.insert("Audio-Latency", latency.to_string());
+        }
+
+        Ok(response)
+    }
 
-        if content_type == "text/parameters" {
-            for line in str::from_utf8(&request.content).unwrap().lines() {
-                let split = line.split(':').collect::<Vec<_>>();
+    async fn handle_pause(&mut self, _: &RtspRequest) -> Result<RtspResponse> {
+        self.send_audio(AudioCommand::FadeOut).await?;
+        self.emit(EventKind::Paused);
 
-                let (key, value) = (split[0].trim().to_owned(), split[1].trim().to_owned());
+        Ok(RtspResponse::new(RtspStatusCode::Ok))
+    }
 
-                match key.as_ref() {
-                    "volume" => {
-                        log::debug!("Set volume {}", value);
-                        let volume = value.parse::<f32>().unwrap();
+    async fn handle_flush(&mut self, _: &RtspRequest) -> Result<RtspResponse> {
+        // audio after flush fades in
+        self.send_audio(AudioCommand::FadeOut).await?;
+        self.emit(EventKind::Flushed);
 
-                        self.session.set_volume(volume);
-                    }
-                    _ => {
-                        log::warn!("Unhandled SET_PARAMETER key {:?}", key);
+        Ok(RtspResponse::new(RtspStatusCode::Ok))
+    }
+
+    async fn handle_teardown(&mut self, _: &RtspRequest) -> Result<RtspResponse> {
+        self.send_audio(AudioCommand::FadeOut).await?;
+        // senders keep the connection between tracks, the speaker is free from now on
+        self.stop_audio().await?;
+        self.context.sessions.release(self.id);
+        self.end(SessionEndReason::Teardown).await;
+
+        Ok(RtspResponse::new(RtspStatusCode::Ok))
+    }
+
+    async fn handle_set_parameter(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
+        let content_type = request.headers.get("Content-Type").ok_or_else(|| bad_request("Missing Content-Type"))?;
+
+        match content_type {
+            "text/parameters" => {
+                let content = str::from_utf8(&request.content).map_err(|_| bad_request("Parameters aren't utf-8"))?;
+
+                for line in content.lines().filter(|x| !x.trim().is_empty()) {
+                    let (key, value) = line.split_once(':').ok_or_else(|| bad_request(format!("Invalid parameter {line:?}")))?;
+                    let (key, value) = (key.trim(), value.trim());
+
+                    match key {
+                        "volume" => {
+                            log::debug!("Set volume {}", value);
+                            let volume = value
+                                .parse::<f32>()
+                                .ok()
+                                .filter(|x| x.is_finite())
+                                .ok_or_else(|| bad_request(format!("Invalid volume {value:?}")))?;
+
+                            self.volume = self.context.volume_curve.gain(volume);
+                            if let Some(audio) = &self.audio {
+                                audio.output.set_volume(self.volume);
+                            }
+                            self.emit(EventKind::VolumeChanged { volume });
+                        }
+                        "progress" => {
+                            let progress = value.split('/').map(|x| x.parse::<u32>()).collect::<Vec<_>>();
+
+                            if let [Ok(start), Ok(current), Ok(end)] = progress[..] {
+                                self.emit(EventKind::Progress { start, current, end });
+                            } else {
+                                log::warn!("Invalid progress {:?}", value);
+                            }
+                        }
+                        _ => {
+                            log::warn!("Unhandled SET_PARAMETER key {:?}", key);
+                        }
                     }
                 }
             }
-
-            Ok(RtspResponse::new(RtspStatusCode::Ok))
-        } else {
-            log::warn!("Unhandled SET_PARAMETER type {:?}", content_type);
-            Ok(RtspResponse::new(RtspStatusCode::Ok))
+            "application/x-dmap-tagged" => match dmap::parse_metadata(&request.content) {
+                Ok(metadata) => {
+                    debug!("metadata: {:?}", metadata);
+                    if metadata != self.metadata {
+                        self.metadata = metadata.clone();
+                        self.context.hooks.run(HookEvent::TrackChange, self.hook_env()).await;
+                    }
+                    self.emit(EventKind::Metadata(metadata));
+                }
+                Err(err) => log::warn!("Invalid metadata {:?}", err),
+            },
+            // image/none is sent when there's no artwork
+            x if x.starts_with("image/") => self.emit(EventKind::Artwork {
+                content_type: content_type.into(),
+                data: request.content.clone(),
+            }),
+            _ => log::warn!("Unhandled SET_PARAMETER type {:?}", content_type),
         }
+
+        Ok(RtspResponse::new(RtspStatusCode::Ok))
     }
 
     async fn handle_announce(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
-        let response = (|| {
-            let sdp = SessionDescription::unmarshal(&mut io::Cursor::new(&request.content)).ok()?;
+        let info = SessionInfo {
+            addr: self.peer_addr,
+            client_name: request.headers.get("X-Apple-Client-Name").map(Into::into),
+            dacp_id: request.headers.get("DACP-ID").map(Into::into),
+            active_remote: request.headers.get("Active-Remote").map(Into::into),
+        };
+        if let Err(holder) = self.context.sessions.acquire(self.id, info, self.stop.clone()) {
+            warn!("Rejecting session {}, speaker is in use by {}", self.id, holder);
 
-            if sdp.media_descriptions.len() != 1 {
-                return None;
-            }
+            return Err(RtspError::new(RtspStatusCode::NotEnoughBandwidth, format!("Speaker is in use by {holder}")).into());
+        }
+
+        let (codec, stream_info) = self.parse_sdp(&request.content)?;
+        self.ended = false;
+        self.emit(EventKind::Announced { codec });
+        self.stream_info = Some(Arc::new(stream_info));
 
-            let codec = sdp.get_codec_for_payload_type(96).ok()?;
-            let media_description = &sdp.media_descriptions[0];
+        Ok(RtspResponse::new(RtspStatusCode::Ok))
+    }
+
+    // returns codec name and stream info
+    fn parse_sdp(&self, content: &[u8]) -> Result<(String, StreamInfo)> {
+        let sdp = SessionDescription::unmarshal(&mut io::Cursor::new(content)).map_err(|err| Error::Sdp(err.to_string()))?;
+        let media_description = match &sdp.media_descriptions[..] {
+            [media_description] => media_description,
+            _ => return Err(Error::Sdp("Expected single media".into()).into()),
+        };
+        let codec = sdp
+            .get_codec_for_payload_type(96)
+            .map_err(|_| Error::Sdp("Missing codec for payload type 96".into()))?;
+
+        // we can't use codec.fmtp here because
+        // https://github.com/webrtc-rs/sdp/blob/v0.5.0/src/util/mod.rs#L148 doesn't work if fmtp has whitespaces
+        let fmtp = media_description.attribute("fmtp").flatten().and_then(|x| x.split_once(' ')).map(|x| x.1);
+
+        debug!("codec: {:?}, fmtp: {:?}", codec, fmtp);
+        let decoder: Box<dyn Decoder> = if let Some(factory) = self.context.decoders.get(&codec.name) {
+            let codec_info = CodecInfo {
+                name: codec.name.clone(),
+                payload_type: codec.payload_type,
+                clock_rate: codec.clock_rate,
+                encoding_parameters: codec.encoding_parameters.clone(),
+                fmtp: fmtp.map(Into::into),
+            };
 
-            debug!("codec: {:?}", codec);
-            let decoder: Box<dyn Decoder> = match codec.name.as_str() {
+            factory(&codec_info)?
+        } else {
+            match codec.name.as_str() {
                 "AppleLossless" => {
-                    // we can't use codec.fmtp here because
-                    // https://github.com/webrtc-rs/sdp/blob/v0.5.0/src/util/mod.rs#L148 doesn't work if fmtp has whitespaces
-                    let fmtp = media_description.attribute("fmtp")??.split_once(' ')?.1;
+                    let fmtp = fmtp.ok_or_else(|| Error::Sdp("Missing fmtp".into()))?;
 
-                    debug!("fmtp: {:?}", fmtp);
-                    Box::new(AppleLoselessDecoder::new(fmtp).ok()?)
+                    Box::new(AppleLoselessDecoder::new(fmtp)?)
                 }
                 "L16" => {
-                    let channels = codec.encoding_parameters.parse().ok()?;
-                    Box::new(RawPCMDecoder::new(AudioFormat::S16BE, channels, codec.clock_rate).ok()?)
+                    let channels = codec.encoding_parameters.parse().map_err(|_| Error::Sdp("Invalid L16 channels".into()))?;
+
+                    Box::new(RawPCMDecoder::new(AudioFormat::S16BE, channels, codec.clock_rate)?)
                 }
-                unk => panic!("Unknown codec {unk:?}"),
-            };
+                name => return Err(RtspError::new(RtspStatusCode::NotImplemented, format!("Unsupported codec {name:?}")).into()),
+            }
+        };
 
-            let rsaaeskey = media_description.attribute("rsaaeskey");
-            let aesiv = media_description.attribute("aesiv");
+        let rsaaeskey = media_description.attribute("rsaaeskey").flatten();
+        let aesiv = media_description.attribute("aesiv").flatten();
 
-            let cipher = if let Some(Some(rsaaeskey)) = rsaaeskey {
-                if let Some(Some(aesiv)) = aesiv {
-                    let rsaaeskey = base64::decode(rsaaeskey).ok()?;
-                    let aesiv = base64::decode(aesiv).ok()?;
+        let cipher = if let (Some(rsaaeskey), Some(aesiv)) = (rsaaeskey, aesiv) {
+            let rsaaeskey = base64::decode(rsaaeskey).map_err(|_| Error::Sdp("Invalid rsaaeskey".into()))?;
+            let aesiv = base64::decode(aesiv).map_err(|_| Error::Sdp("Invalid aesiv".into()))?;
 
-                    debug!("key: {:?}, iv: {:?}", rsaaeskey, aesiv);
+            debug!("key: {:?}, iv: {:?}", rsaaeskey, aesiv);
 
-                    Some(RsaAesCipher::new(&rsaaeskey, &aesiv).ok()?)
-                } else {
-                    None
-                }
+            Some(RsaAesCipher::new(&self.context.key, &rsaaeskey, &aesiv)?)
+        } else {
+            None
+        };
+
+        let stream_info = StreamInfo {
+            rtp_type: codec.payload_type,
+            decoder,
+            cipher,
+        };
+
+        Ok((codec.name, stream_info))
+    }
+
+    async fn handle_setup(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
+        let client_transport = request.headers.get("Transport").ok_or_else(|| bad_request("Missing Transport"))?;
+        debug!("client_transport: {:?}", client_transport);
+
+        let transports = client_transport
+            .split(';')
+            .map(|x| x.split('=').collect::<Vec<_>>())
+            .map(|x| if x.len() == 2 { (x[0], x[1]) } else { (x[0], "") })
+            .collect::<HashMap<_, _>>();
+
+        // repeated SETUP replaces previous transport
+        self.release_ports();
+
+        let transport = if transports.contains_key("RTP/AVP/TCP") {
+            if !self.context.tcp_transport {
+                return Err(RtspError::new(RtspStatusCode::UnsupportedTransport, "RTP over TCP is disabled").into());
+            }
+
+            if let Some(interleaved) = transports.get("interleaved") {
+                let (rtp, control) = interleaved
+                    .split_once('-')
+                    .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                    .ok_or_else(|| bad_request(format!("Invalid interleaved channels {interleaved:?}")))?;
+                self.interleaved = Some((rtp, control));
+
+                format!("RTP/AVP/TCP;unicast;mode=record;interleaved={rtp}-{control}")
             } else {
-                None
-            };
+                let listener = self.context.ports.listen_tcp(self.local_addr).map_err(|err| self.refuse_setup(err))?;
+                let port = listener.local_addr()?.port();
+                self.ports = vec![port];
+                self.data_listener = Some(listener);
 
-            self.stream_info = Some(StreamInfo {
-                rtp_type: codec.payload_type,
-                decoder,
-                cipher,
-                session: self.session.clone(),
+                format!("RTP/AVP/TCP;unicast;mode=record;server_port={port}")
+            }
+        } else {
+            debug!("client_control_port: {:?}", transports.get("control_port"));
+            debug!("client_timing_port: {:?}", transports.get("timing_port"));
+
+            let sockets = self.context.ports.bind_udp(self.local_addr, 3).map_err(|err| self.refuse_setup(err))?;
+            self.ports = sockets.iter().map(|x| x.local_addr().map(|x| x.port())).collect::<Result<_, _>>()?;
+
+            let transport = format!(
+                "RTP/AVP/UDP;unicast;mode=record;server_port={};control_port={};timing_port={}",
+                self.ports[0], self.ports[1], self.ports[2]
+            );
+
+            let [rtp, control, timing]: [UdpSocket; 3] = sockets.try_into().map_err(|_| anyhow!("Unexpected udp socket count"))?;
+            self.udp_sockets = Some(UdpSockets { rtp, control, timing });
+
+            transport
+        };
+
+        let response_headers = hashmap! {
+            "Session" => self.id.to_string(),
+            "Transport" => transport
+        };
+
+        Ok(RtspResponse::with_headers(RtspStatusCode::Ok, response_headers))
+    }
+
+    fn refuse_setup(&self, err: anyhow::Error) -> RtspError {
+        warn!("Refusing setup of session {}: {:?}", self.id, err);
+
+        RtspError::new(RtspStatusCode::NotEnoughBandwidth, "No free port")
+    }
+}
+
+fn bad_request(reason: impl Into<String>) -> RtspError {
+    RtspError::new(RtspStatusCode::BadRequest, reason)
+}
+
+// anyone can send anything to our ports, so invalid packets are dropped instead of ending the session
+fn valid<T>(packet: crate::Result<Option<T>>) -> Option<T> {
+    packet.unwrap_or_else(|err| {
+        debug!("Dropping invalid packet: {:?}", err);
+
+        None
+    })
+}
+
+// source is kept, so that strays and packets from others don't keep the session alive
+fn datagram<T>(packet: Option<crate::Result<(T, SocketAddr)>>) -> Result<Option<(T, SocketAddr)>> {
+    match packet {
+        Some(packet) => Ok(valid(packet.map(Some))),
+        None => Err(anyhow!("Udp socket closed")),
+    }
+}
+
+// also resolves if shutdown began before we subscribed
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            future::pending().await
+        }
+    }
+}
+
+// pending until transport is set up at SETUP
+async fn next_packet<S: Stream + Unpin>(stream: &mut Option<S>) -> Option<S::Item> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => future::pending().await,
+    }
+}
+
+async fn accept(listener: &mut Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    use crate::{
+        event::{Event, EventKind},
+        hook::Hooks,
+        receiver::Receiver,
+        sessions::SessionPolicy,
+        sink::{DummyAudioSink, RecordingSink},
+    };
+    use std::sync::atomic::Ordering;
+
+    const SDP: &str = "v=0\r\no=- 1 0 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio 0 RTP/AVP 96\r\n";
+
+    fn request(method: &str, headers: &str, content: &[u8]) -> Vec<u8> {
+        let mut request = format!("{method} rtsp://127.0.0.1/1 RTSP/1.0\r\nCSeq: 1\r\n{headers}");
+        if !content.is_empty() {
+            request += &format!("Content-Length: {}\r\n", content.len());
+        }
+        request += "\r\n";
+
+        [request.as_bytes(), content].concat()
+    }
+
+    fn announce(attributes: &str) -> Vec<u8> {
+        request("ANNOUNCE", "Content-Type: application/sdp\r\n", format!("{SDP}{attributes}").as_bytes())
+    }
+
+    fn set_parameter(content_type: &str, content: &[u8]) -> Vec<u8> {
+        request("SET_PARAMETER", &format!("Content-Type: {content_type}\r\n"), content)
+    }
+
+    fn interleaved(channel: u8, data: &[u8]) -> Vec<u8> {
+        [&[b'$', channel][..], &(data.len() as u16).to_be_bytes(), data].concat()
+    }
+
+    // announce, interleaved setup, record, audio, metadata and teardown
+    fn valid_session() -> Vec<Vec<u8>> {
+        vec![
+            announce("a=rtpmap:96 L16/44100/2\r\n"),
+            request("SETUP", "Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n", &[]),
+            request("RECORD", "Session: 1\r\n", &[]),
+            interleaved(0, &[[0x80, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0].as_slice(), &[0; 16]].concat()),
+            set_parameter("text/parameters", b"volume: -15.0\r\n"),
+            set_parameter("application/x-dmap-tagged", b"minm\x00\x00\x00\x05Title"),
+            request("TEARDOWN", "", &[]),
+        ]
+    }
+
+    fn corpus() -> Vec<Vec<u8>> {
+        let session = valid_session();
+        let setup = [session[0].clone(), session[1].clone()].concat();
+
+        let cases = [
+            b"\r\n\r\n".to_vec(),
+            b"OPTIONS\r\n\r\n".to_vec(),
+            b"OPTIONS * RTSP/1.0\r\nCSeq\r\n\r\n".to_vec(),
+            b"OPTIONS * RTSP/1.0\r\n\xff: \xfe\r\n\r\n".to_vec(),
+            b"OPTIONS * RTSP/1.0\r\nContent-Length: 99\r\n\r\n".to_vec(),
+            request("OPTIONS", "Apple-Challenge: !!!\r\n", &[]),
+            request("ANNOUNCE", "", b"v=0"),
+            announce(""),
+            announce("a=rtpmap:96 Opus/48000/2\r\n"),
+            announce("a=rtpmap:96 AppleLossless\r\na=fmtp:96 352 0 16\r\n"),
+            announce("a=rtpmap:96 AppleLossless\r\na=fmtp:96 352 0 16 40 10 14 0 255 0 0 0\r\n"),
+            announce("a=rtpmap:96 L16/44100/0\r\n"),
+            announce("a=rtpmap:96 L16/44100/x\r\n"),
+            announce("a=rtpmap:96 L16/44100/2\r\na=rsaaeskey:!!\r\na=aesiv:AAAA\r\n"),
+            announce("a=rtpmap:96 L16/44100/2\r\na=rsaaeskey:AAAA\r\na=aesiv:AAAA\r\n"),
+            [session[0].clone(), request("SETUP", "", &[])].concat(),
+            [session[0].clone(), request("SETUP", "Transport: RTP/AVP/TCP;interleaved=a-b\r\n", &[])].concat(),
+            [setup.clone(), request("RECORD", "Session: 2;timeout=60\r\n", &[])].concat(),
+            [setup.clone(), set_parameter("text/parameters", b"volume")].concat(),
+            [setup.clone(), set_parameter("text/parameters", b"volume: nan")].concat(),
+            [setup.clone(), set_parameter("text/parameters", b"progress: 1/2")].concat(),
+            [setup.clone(), set_parameter("text/parameters", b"\xff\xfe")].concat(),
+            [setup.clone(), set_parameter("application/x-dmap-tagged", b"mlit\x00\x00\x00\x09minm")].concat(),
+            [
+                setup.clone(),
+                set_parameter("application/x-dmap-tagged", &b"mlit\x00\x00\x00\x00".repeat(100)),
+            ]
+            .concat(),
+            [
+                setup.clone(),
+                request("RECORD", "", &[]),
+                interleaved(0, &[]),
+                interleaved(0, &[0x80]),
+                interleaved(1, &[0x80, 0xd4, 0]),
+            ]
+            .concat(),
+            [
+                setup.clone(),
+                request("RECORD", "", &[]),
+                interleaved(0, &[0x80, 0x61, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+            ]
+            .concat(),
+            [setup, request("RECORD", "", &[]), b"$\x00\xff\xff".to_vec()].concat(),
+        ];
+
+        // deterministic mutations of a valid session, xorshift so that failures reproduce
+        let valid = session.concat();
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut random = move |max: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % max as u64) as usize
+        };
+        let mutations = (0..200).map(|_| {
+            let mut data = valid.clone();
+            for _ in 0..=random(4) {
+                let position = random(data.len());
+                match random(3) {
+                    0 => data[position] = random(256) as u8,
+                    1 => data.truncate(position),
+                    _ => {
+                        let alphabet = b"\r\n:;-$ 0123456789";
+                        data.insert(position, alphabet[random(alphabet.len())]);
+                    }
+                }
+                if data.is_empty() {
+                    break;
+                }
+            }
+
+            data
+        });
+
+        cases.into_iter().chain([valid.clone()]).chain(mutations).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_malformed_input() -> Result<()> {
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .sink(Arc::new(DummyAudioSink::default()))
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        for (i, input) in corpus().iter().enumerate() {
+            let mut stream = TcpStream::connect(handle.local_addr()).await?;
+            stream.write_all(input).await?;
+            stream.shutdown().await?;
+            // connection may be reset when session ends before reading everything
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+
+            // panicking session would never report its end
+            let ended = timeout(Duration::from_secs(5), async {
+                loop {
+                    if let EventKind::SessionEnded { .. } = events.recv().await?.kind {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
             });
+            ended
+                .await
+                .map_err(|_| anyhow!("Session didn't end on input {i}: {:?}", String::from_utf8_lossy(input)))??;
+        }
 
-            Some(RtspResponse::new(RtspStatusCode::Ok))
-        })();
+        handle.shutdown().await
+    }
 
-        if let Some(response) = response {
-            Ok(response)
-        } else {
-            Ok(RtspResponse::new(RtspStatusCode::BadRequest))
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_timeout() -> Result<()> {
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .idle_timeout(Duration::from_millis(300))
+            .sink(Arc::new(DummyAudioSink::default()))
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        // keep-alive requests keep the session open past the timeout
+        let mut stream = TcpStream::connect(handle.local_addr()).await?;
+        for _ in 0..6 {
+            stream.write_all(&request("OPTIONS", "", &[])).await?;
+            let _ = stream.read(&mut [0; 1024]).await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event.kind, EventKind::SessionEnded { .. }), "{:?}", event.kind);
         }
+
+        // then sender goes silent without closing the connection
+        let reason = timeout(Duration::from_secs(5), async {
+            loop {
+                if let EventKind::SessionEnded { reason } = events.recv().await?.kind {
+                    return Ok::<_, anyhow::Error>(reason);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(reason, SessionEndReason::Timeout);
+        assert_eq!(stream.read(&mut [0; 1024]).await?, 0);
+
+        handle.shutdown().await
     }
 
-    async fn handle_setup(&mut self, request: &RtspRequest) -> Result<RtspResponse> {
-        if let Some(client_transport) = request.headers.get("Transport") {
-            debug!("client_transport: {:?}", client_transport);
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_timeout_ignores_strays() -> Result<()> {
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .idle_timeout(Duration::from_millis(300))
+            .sink(Arc::new(DummyAudioSink::default()))
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
 
-            let transports = client_transport
-                .split(';')
-                .map(|x| x.split('=').collect::<Vec<_>>())
-                .map(|x| if x.len() == 2 { (x[0], x[1]) } else { (x[0], "") })
-                .collect::<HashMap<_, _>>();
+        let mut rtsp = TcpStream::connect(handle.local_addr()).await?;
+        let mut response = [0; 1024];
+        rtsp.write_all(&announce("a=rtpmap:96 L16/44100/2\r\n")).await?;
+        let _ = rtsp.read(&mut response).await?;
+        rtsp.write_all(&request(
+            "SETUP",
+            "Transport: RTP/AVP/UDP;unicast;mode=record;control_port=6001;timing_port=6002\r\n",
+            &[],
+        ))
+        .await?;
+        let length = rtsp.read(&mut response).await?;
+        let response = String::from_utf8_lossy(&response[..length]).into_owned();
+        let port = response
+            .split("server_port=")
+            .nth(1)
+            .and_then(|x| x.split(|x: char| !x.is_ascii_digit()).next())
+            .ok_or_else(|| anyhow!("No server_port in {response:?}"))?
+            .parse::<u16>()?;
 
-            let client_control_port = transports.get("control_port").unwrap();
-            let client_timing_port = transports.get("timing_port").unwrap();
+        // valid packets from another host and garbage from the sender don't count as activity
+        let packet = [[0x80, 0x60, 0, 1].as_slice(), &[0; 8], &[0x10; 400]].concat();
+        let intruder = tokio::net::UdpSocket::bind("127.0.0.2:0").await?;
+        let garbage = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let target = SocketAddr::new("127.0.0.1".parse()?, port);
+        let strays = tokio::spawn(async move {
+            for _ in 0..60 {
+                intruder.send_to(&packet, target).await?;
+                garbage.send_to(&[0; 2], target).await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
 
-            debug!("client_control_port: {}", client_control_port);
-            debug!("client_timing_port: {}", client_timing_port);
+            Ok::<_, anyhow::Error>(())
+        });
 
-            let transport = format!(
-                "RTP/AVP/UDP;unicast;mode=record;server_port={};control_port={};timing_port={}",
-                self.rtp_port, self.control_port, self.timing_port
+        let reason = timeout(Duration::from_secs(2), async {
+            loop {
+                if let EventKind::SessionEnded { reason } = events.recv().await?.kind {
+                    return Ok::<_, anyhow::Error>(reason);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(reason, SessionEndReason::Timeout);
+        strays.abort();
+
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rtp_from_peer_only() -> Result<()> {
+        let sink = Arc::new(RecordingSink::default());
+        let receiver = Receiver::builder().port(0).bind("127.0.0.1".parse()?).sink(sink.clone()).build()?;
+        let handle = receiver.start().await?;
+
+        let mut rtsp = TcpStream::connect(handle.local_addr()).await?;
+        let mut response = [0; 1024];
+        rtsp.write_all(&announce("a=rtpmap:96 L16/44100/2\r\n")).await?;
+        let _ = rtsp.read(&mut response).await?;
+        rtsp.write_all(&request(
+            "SETUP",
+            "Transport: RTP/AVP/UDP;unicast;mode=record;control_port=6001;timing_port=6002\r\n",
+            &[],
+        ))
+        .await?;
+        let length = rtsp.read(&mut response).await?;
+        let response = String::from_utf8_lossy(&response[..length]).into_owned();
+        let port = response
+            .split("server_port=")
+            .nth(1)
+            .and_then(|x| x.split(|x: char| !x.is_ascii_digit()).next())
+            .ok_or_else(|| anyhow!("No server_port in {response:?}"))?
+            .parse::<u16>()?;
+        rtsp.write_all(&request("RECORD", "Session: 1\r\n", &[])).await?;
+        let _ = rtsp.read(&mut [0; 1024]).await?;
+
+        // 100 frames of stereo L16 each, another host sends first
+        let packet = [[0x80, 0x60, 0, 1].as_slice(), &[0; 8], &[0x10; 400]].concat();
+        let intruder = tokio::net::UdpSocket::bind("127.0.0.2:0").await?;
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let target = SocketAddr::new("127.0.0.1".parse()?, port);
+        for _ in 0..10 {
+            intruder.send_to(&packet, target).await?;
+        }
+        for _ in 0..10 {
+            sender.send_to(&packet, target).await?;
+        }
+
+        // every packet is in except 10ms held back for fade out
+        timeout(Duration::from_secs(5), async {
+            while sink.samples.lock().unwrap().len() < 10 * 200 - 441 * 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        rtsp.write_all(&request("TEARDOWN", "Session: 1\r\n", &[])).await?;
+        let _ = rtsp.read(&mut [0; 1024]).await?;
+
+        assert_eq!(sink.samples.lock().unwrap().len(), 10 * 200);
+
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown() -> Result<()> {
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .sink(Arc::new(DummyAudioSink::default()))
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        let mut stream = TcpStream::connect(handle.local_addr()).await?;
+        stream.write_all(&request("OPTIONS", "", &[])).await?;
+        let _ = stream.read(&mut [0; 1024]).await?;
+
+        // returns once the session ended
+        timeout(Duration::from_secs(5), handle.shutdown()).await??;
+        assert_eq!(stream.read(&mut [0; 1024]).await?, 0);
+
+        let mut reasons = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let EventKind::SessionEnded { reason } = event.kind {
+                reasons.push(reason);
+            }
+        }
+        assert_eq!(reasons, vec![SessionEndReason::Shutdown]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audio() -> Result<()> {
+        let sink = Arc::new(RecordingSink::default());
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .sink(sink.clone())
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        // 100 frames of stereo L16 per packet
+        let packets = (0..10u16)
+            .map(|i| interleaved(0, &[[0x80, 0x60].as_slice(), &i.to_be_bytes(), &[0; 8], &[0x10; 400]].concat()))
+            .collect::<Vec<_>>();
+        let session = valid_session();
+        let input = [&session[..3], &packets, &session[6..]].concat().concat();
+
+        let mut stream = TcpStream::connect(handle.local_addr()).await?;
+        stream.write_all(&input).await?;
+        stream.shutdown().await?;
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let EventKind::SessionEnded { reason } = events.recv().await?.kind {
+                    return Ok::<_, anyhow::Error>(reason);
+                }
+            }
+        })
+        .await??;
+
+        // every sample is decoded and written by the time the session ends, including what's held back for fade out
+        assert_eq!(sink.samples.lock().unwrap().len(), 10 * 200);
+
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_data_connection_from_peer_only() -> Result<()> {
+        let sink = Arc::new(RecordingSink::default());
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .sink(sink.clone())
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        let mut rtsp = TcpStream::connect(handle.local_addr()).await?;
+        let mut response = [0; 1024];
+        rtsp.write_all(&announce("a=rtpmap:96 L16/44100/2\r\n")).await?;
+        let _ = rtsp.read(&mut response).await?;
+        rtsp.write_all(&request("SETUP", "Transport: RTP/AVP/TCP;unicast;mode=record\r\n", &[]))
+            .await?;
+        let length = rtsp.read(&mut response).await?;
+        let response = String::from_utf8_lossy(&response[..length]).into_owned();
+        let port = response
+            .split("server_port=")
+            .nth(1)
+            .and_then(|x| x.split(|x: char| !x.is_ascii_digit()).next())
+            .ok_or_else(|| anyhow!("No server_port in {response:?}"))?
+            .parse::<u16>()?;
+        rtsp.write_all(&request("RECORD", "Session: 1\r\n", &[])).await?;
+        let _ = rtsp.read(&mut [0; 1024]).await?;
+
+        // 100 frames of stereo L16, length prefixed
+        let packet = [[0x80, 0x60, 0, 1].as_slice(), &[0; 8], &[0x10; 400]].concat();
+        let frame = [&(packet.len() as u16).to_be_bytes(), packet.as_slice()].concat();
+
+        // another host reaching the port first is disconnected
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind("127.0.0.2:0".parse()?)?;
+        let mut intruder = socket.connect(SocketAddr::new("127.0.0.1".parse()?, port)).await?;
+        let _ = intruder.write_all(&frame).await;
+        assert_eq!(timeout(Duration::from_secs(5), intruder.read(&mut [0; 16])).await?.unwrap_or(0), 0);
+
+        let mut data = TcpStream::connect(SocketAddr::new("127.0.0.1".parse()?, port)).await?;
+        data.write_all(&frame.repeat(10)).await?;
+        data.shutdown().await?;
+        // TEARDOWN stops the sink right away, wait until every frame is in except 10ms held back for fade out
+        timeout(Duration::from_secs(5), async {
+            while sink.samples.lock().unwrap().len() < 10 * 200 - 441 * 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        rtsp.write_all(&request("TEARDOWN", "Session: 1\r\n", &[])).await?;
+        rtsp.shutdown().await?;
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let EventKind::SessionEnded { .. } = events.recv().await?.kind {
+                    return Ok::<_, anyhow::Error>(());
+                }
+            }
+        })
+        .await??;
+
+        assert_eq!(sink.samples.lock().unwrap().len(), 10 * 200);
+
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sink_started_at_record() -> Result<()> {
+        let sink = Arc::new(RecordingSink::default());
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .session_policy(SessionPolicy::Reject)
+            .sink(sink.clone())
+            .build()?;
+        let handle = receiver.start().await?;
+
+        let session = valid_session();
+        let mut playing = TcpStream::connect(handle.local_addr()).await?;
+        for request in &session[..3] {
+            playing.write_all(request).await?;
+            let _ = playing.read(&mut [0; 1024]).await?;
+        }
+
+        // refused with 453, and connection which never announces
+        let mut refused = TcpStream::connect(handle.local_addr()).await?;
+        refused.write_all(&session[0]).await?;
+        let mut response = [0; 1024];
+        let length = refused.read(&mut response).await?;
+        assert!(response[..length].starts_with(b"RTSP/1.0 453"));
+
+        let mut idle = TcpStream::connect(handle.local_addr()).await?;
+        idle.write_all(&request("OPTIONS", "", &[])).await?;
+        let _ = idle.read(&mut [0; 1024]).await?;
+
+        assert_eq!(sink.starts.load(Ordering::Relaxed), 1);
+
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_teardown_keeping_connection() -> Result<()> {
+        let sink = Arc::new(RecordingSink::default());
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .session_policy(SessionPolicy::Reject)
+            .sink(sink.clone())
+            .build()?;
+        let handle = receiver.start().await?;
+
+        let session = valid_session();
+        let mut first = TcpStream::connect(handle.local_addr()).await?;
+        for request in &session[..3] {
+            first.write_all(request).await?;
+            let _ = first.read(&mut [0; 1024]).await?;
+        }
+        first.write_all(&[session[3].clone(), session[6].clone()].concat()).await?;
+        let mut response = [0; 1024];
+        let length = first.read(&mut response).await?;
+        assert!(response[..length].starts_with(b"RTSP/1.0 200"));
+
+        // sink session is done with, while the connection stays open
+        assert_eq!(sink.samples.lock().unwrap().len(), 8);
+
+        let mut second = TcpStream::connect(handle.local_addr()).await?;
+        for request in [&session[0], &session[1], &request("RECORD", "Session: 2\r\n", &[])] {
+            second.write_all(request).await?;
+            let length = second.read(&mut response).await?;
+            assert!(
+                response[..length].starts_with(b"RTSP/1.0 200"),
+                "{:?}",
+                String::from_utf8_lossy(&response[..length])
             );
+        }
+        assert_eq!(sink.starts.load(Ordering::Relaxed), 2);
 
-            let response_headers = hashmap! {
-                "Session" => self.id.to_string(),
-                "Transport" => transport
-            };
+        // first one can't take the speaker back while second one plays
+        first.write_all(&session[0]).await?;
+        let length = first.read(&mut response).await?;
+        assert!(response[..length].starts_with(b"RTSP/1.0 453"));
 
-            Ok(RtspResponse::with_headers(RtspStatusCode::Ok, response_headers))
-        } else {
-            Ok(RtspResponse::new(RtspStatusCode::BadRequest))
+        handle.shutdown().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_end_hook_at_teardown() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("ras_end_hook_test_{}", std::process::id()));
+        let receiver = Receiver::builder()
+            .port(0)
+            .bind("127.0.0.1".parse()?)
+            .tcp_transport(true)
+            .hooks(Hooks {
+                session_end: Some(format!("echo $RAS_END_REASON >> {}", path.display())),
+                wait: true,
+                ..Default::default()
+            })
+            .sink(Arc::new(DummyAudioSink::default()))
+            .build()?;
+        let handle = receiver.start().await?;
+        let mut events = handle.events();
+
+        let session = valid_session();
+        let mut stream = TcpStream::connect(handle.local_addr()).await?;
+        for request in [&session[0], &session[1], &session[2], &session[6]] {
+            stream.write_all(request).await?;
+            let _ = stream.read(&mut [0; 1024]).await?;
+        }
+
+        // while the sender keeps the connection
+        assert_eq!(std::fs::read_to_string(&path)?, "teardown\n");
+        let ended = |events: &mut tokio::sync::broadcast::Receiver<Event>| {
+            let mut reasons = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                if let EventKind::SessionEnded { reason } = event.kind {
+                    reasons.push(reason);
+                }
+            }
+            reasons
+        };
+        assert_eq!(ended(&mut events), [SessionEndReason::Teardown]);
+
+        // and not again once it's closed
+        drop(stream);
+        timeout(Duration::from_secs(5), handle.shutdown()).await??;
+        assert_eq!(std::fs::read_to_string(&path)?, "teardown\n");
+        assert!(ended(&mut events).is_empty());
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_state_machine() -> Result<()> {
+        let mut state = SessionState::Init;
+        for (method, expected) in [
+            ("OPTIONS", SessionState::Init),
+            ("ANNOUNCE", SessionState::Announced),
+            ("SETUP", SessionState::Ready),
+            ("SET_PARAMETER", SessionState::Ready),
+            ("RECORD", SessionState::Playing),
+            ("FLUSH", SessionState::Playing),
+            ("PAUSE", SessionState::Paused),
+            ("RECORD", SessionState::Playing),
+            ("TEARDOWN", SessionState::TornDown),
+            ("ANNOUNCE", SessionState::Announced),
+        ] {
+            state = state.next(method)?.unwrap_or(state);
+            assert_eq!(state, expected, "after {method}");
+        }
+
+        for (method, state) in [
+            ("SETUP", SessionState::Init),
+            ("RECORD", SessionState::Announced),
+            ("PAUSE", SessionState::Ready),
+            ("ANNOUNCE", SessionState::Playing),
+            ("TEARDOWN", SessionState::TornDown),
+        ] {
+            let err = state.next(method).unwrap_err();
+            assert_eq!(err.status, RtspStatusCode::MethodNotValidInThisState, "{method} in {state:?}");
         }
+
+        Ok(())
     }
 }
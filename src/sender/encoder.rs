@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+// frames per packet, as announced in alac fmtp
+pub const FRAMES_PER_PACKET: usize = 352;
+
+// alac element tags
+const ID_CPE: u32 = 1;
+const ID_END: u32 = 7;
+
+// codec of the stream we send, 16 bit stereo at 44100Hz
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    // uncompressed alac frames, accepted by every airplay receiver
+    #[default]
+    Alac,
+    // big endian pcm
+    L16,
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "alac" => Ok(Self::Alac),
+            "l16" => Ok(Self::L16),
+            _ => Err(anyhow!("Unknown codec {s:?}, expected alac or l16")),
+        }
+    }
+}
+
+impl Codec {
+    // sdp attributes describing payload type 96
+    pub fn sdp_attributes(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::Alac => vec![
+                ("rtpmap", "96 AppleLossless".into()),
+                ("fmtp", format!("96 {FRAMES_PER_PACKET} 0 16 40 10 14 2 255 0 0 44100")),
+            ],
+            Self::L16 => vec![("rtpmap", "96 L16/44100/2".into())],
+        }
+    }
+
+    // interleaved stereo samples, up to FRAMES_PER_PACKET frames
+    pub fn encode(&self, samples: &[i16]) -> Vec<u8> {
+        match self {
+            Self::Alac => encode_alac(samples),
+            Self::L16 => samples.iter().flat_map(|x| x.to_be_bytes()).collect(),
+        }
+    }
+}
+
+// alac frame with verbatim samples, so we don't need a real encoder
+fn encode_alac(samples: &[i16]) -> Vec<u8> {
+    let frames = samples.len() / 2;
+    let partial = frames != FRAMES_PER_PACKET;
+
+    let mut writer = BitWriter::default();
+    writer.write(ID_CPE, 3);
+    writer.write(0, 4); // element instance tag
+    writer.write(0, 12); // unused
+    writer.write(partial as u32, 1); // frame length follows
+    writer.write(0, 2); // uncompressed bytes
+    writer.write(1, 1); // not compressed
+    if partial {
+        writer.write(frames as u32, 32);
+    }
+    for sample in samples {
+        writer.write(*sample as u16 as u32, 16);
+    }
+    writer.write(ID_END, 3);
+
+    writer.finish()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // bits used in the last byte, 0 ~ 7
+    used: u32,
+}
+
+impl BitWriter {
+    // msb first
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::{AppleLoselessDecoder, Decoder};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_alac() -> Result<()> {
+        let decoder = AppleLoselessDecoder::new("352 0 16 40 10 14 2 255 0 0 44100")?;
+
+        let samples = (0..FRAMES_PER_PACKET as i16 * 2).map(|x| x.wrapping_mul(91)).collect::<Vec<_>>();
+        let decoded = decoder.decode(&Codec::Alac.encode(&samples))?;
+        assert_eq!(decoded, samples.iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>());
+
+        // last packet of a stream is shorter
+        let decoded = decoder.decode(&Codec::Alac.encode(&samples[..20]))?;
+        assert_eq!(decoded, samples[..20].iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_l16() -> Result<()> {
+        assert_eq!(Codec::L16.encode(&[1, -2]), [0, 1, 0xff, 0xfe]);
+        assert_eq!(Codec::L16.sdp_attributes(), [("rtpmap", "96 L16/44100/2".into())]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+mod encoder;
+mod wav;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use log::{debug, info, trace, warn};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    RsaPublicKey,
+};
+use sdp::{
+    description::{
+        common::{Address, ConnectionInformation},
+        media::{MediaName, RangedPort},
+        session::{Origin, TimeDescription, Timing},
+    },
+    MediaDescription, SessionDescription,
+};
+use tokio::{
+    io::AsyncRead,
+    net::{TcpStream, UdpSocket},
+    time::{sleep, sleep_until, Instant},
+};
+use tokio_util::codec::{Encoder, Framed};
+
+pub use encoder::Codec;
+pub use wav::WavReader;
+
+use crate::{
+    cipher::{RsaAesCipher, KEY},
+    mdns,
+    rtp::{
+        RtpCodec, RtpControlCodec, RtpControlPacket, RtpPacket, RtpTimingCodec, RtpTimingPacket, PAYLOAD_TYPE_TIMING_REQUEST,
+        PAYLOAD_TYPE_TIMING_RESPONSE,
+    },
+    rtsp::{RtspCodec, RtspHeaders, RtspMessage, RtspRequest},
+};
+use encoder::FRAMES_PER_PACKET;
+
+const SAMPLE_RATE: u32 = 44100;
+const PAYLOAD_TYPE: u8 = 96;
+// used when receiver doesn't tell its latency in RECORD response
+const DEFAULT_LATENCY: u32 = 11025;
+const SYNC_INTERVAL: u32 = SAMPLE_RATE;
+const USER_AGENT: &str = concat!("ras/", env!("CARGO_PKG_VERSION"));
+// seconds between 1900 and 1970
+const NTP_EPOCH_OFFSET: u64 = 2208988800;
+
+pub struct SenderBuilder {
+    codec: Codec,
+    encrypt: bool,
+    key: Option<RsaPublicKey>,
+    volume: Option<f32>,
+}
+
+impl SenderBuilder {
+    fn new() -> Self {
+        Self {
+            codec: Codec::default(),
+            encrypt: false,
+            key: None,
+            volume: None,
+        }
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // rsa/aes encrypts audio, which airport express and older receivers require
+    pub fn encrypt(mut self, encrypt: bool) -> Self {
+        self.encrypt = encrypt;
+        self
+    }
+
+    // receiver's public key for encrypting the stream key, defaults to the airport express key
+    pub fn key(mut self, key: RsaPublicKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    // airplay volume, -30.0 ~ 0.0 or -144.0 for mute
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.volume = Some(volume);
+        self
+    }
+
+    pub fn build(self) -> Sender {
+        Sender {
+            codec: self.codec,
+            key: self.encrypt.then(|| self.key.unwrap_or_else(|| KEY.to_public_key())),
+            volume: self.volume,
+        }
+    }
+}
+
+// streams audio to an airplay receiver over rtsp and udp, like itunes does
+pub struct Sender {
+    codec: Codec,
+    key: Option<RsaPublicKey>,
+    volume: Option<f32>,
+}
+
+impl Sender {
+    pub fn builder() -> SenderBuilder {
+        SenderBuilder::new()
+    }
+
+    // plays the whole source unless `stop` resolves first, session is torn down either way
+    pub async fn send<R: AsyncRead + Unpin>(&self, addr: SocketAddr, source: &mut WavReader<R>, stop: impl Future<Output = ()>) -> Result<()> {
+        let stream = TcpStream::connect(addr).await.with_context(|| format!("Can't connect to {addr}"))?;
+        let local_addr = stream.local_addr()?;
+        let mut client = RtspClient::new(stream, local_addr.ip());
+        info!("Connected to {}", addr);
+
+        client.request("OPTIONS", "*", RtspHeaders::new(), Vec::new()).await?;
+
+        let (sdp, cipher) = self.sdp(local_addr.ip(), addr.ip(), client.session_id)?;
+        let mut headers = RtspHeaders::new();
+        headers.append("Content-Type", "application/sdp");
+        client.request("ANNOUNCE", &client.url(), headers, sdp.into_bytes()).await?;
+
+        let data = UdpSocket::bind(udp_addr(local_addr)).await?;
+        let control = UdpSocket::bind(udp_addr(local_addr)).await?;
+        let timing = UdpSocket::bind(udp_addr(local_addr)).await?;
+
+        let mut headers = RtspHeaders::new();
+        let transport = format!(
+            "RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={};timing_port={}",
+            control.local_addr()?.port(),
+            timing.local_addr()?.port()
+        );
+        headers.append("Transport", &transport);
+        let response = client.request("SETUP", &client.url(), headers, Vec::new()).await?;
+        let ports = ServerPorts::parse(response.get("Transport").ok_or_else(|| anyhow!("Missing Transport in SETUP response"))?)?;
+        client.session = Some(
+            response
+                .get("Session")
+                .ok_or_else(|| anyhow!("Missing Session in SETUP response"))?
+                .into(),
+        );
+        debug!("Server ports {:?}", ports);
+
+        let sequence = OsRng.next_u32() as u16;
+        let rtptime = OsRng.next_u32();
+        let mut headers = RtspHeaders::new();
+        headers.append("Range", "npt=0-");
+        headers.append("RTP-Info", &format!("seq={sequence};rtptime={rtptime}"));
+        let response = client.request("RECORD", &client.url(), headers, Vec::new()).await?;
+        let latency = response.get("Audio-Latency").and_then(|x| x.parse().ok()).unwrap_or(DEFAULT_LATENCY);
+
+        if let Some(volume) = self.volume {
+            let mut headers = RtspHeaders::new();
+            headers.append("Content-Type", "text/parameters");
+            let content = format!("volume: {volume:.6}\r\n").into_bytes();
+            client.request("SET_PARAMETER", &client.url(), headers, content).await?;
+        }
+
+        let stream = AudioStream {
+            codec: self.codec,
+            cipher,
+            data,
+            control,
+            data_addr: SocketAddr::new(addr.ip(), ports.server_port),
+            control_addr: SocketAddr::new(addr.ip(), ports.control_port),
+            sequence,
+            rtptime,
+            latency,
+        };
+
+        let result = select! {
+            result = stream.play(source).fuse() => result,
+            result = serve_timing(&timing).fuse() => result,
+            _ = Box::pin(stop.fuse()) => {
+                info!("Stopping playback");
+                Ok(())
+            },
+        };
+
+        if let Err(err) = client.request("TEARDOWN", &client.url(), RtspHeaders::new(), Vec::new()).await {
+            warn!("TEARDOWN failed: {:?}", err);
+        }
+
+        result
+    }
+
+    // returns sdp of ANNOUNCE and the cipher matching its key
+    fn sdp(&self, local: IpAddr, remote: IpAddr, session_id: u32) -> Result<(String, Option<RsaAesCipher>)> {
+        let address_type = |addr: IpAddr| if addr.is_ipv4() { "IP4" } else { "IP6" }.to_owned();
+
+        let mut media = MediaDescription {
+            media_name: MediaName {
+                media: "audio".into(),
+                port: RangedPort { value: 0, range: None },
+                protos: vec!["RTP".into(), "AVP".into()],
+                formats: vec![PAYLOAD_TYPE.to_string()],
+            },
+            ..Default::default()
+        };
+        for (key, value) in self.codec.sdp_attributes() {
+            media = media.with_value_attribute(key.into(), value);
+        }
+
+        let cipher = match &self.key {
+            Some(key) => {
+                let (cipher, rsaaeskey, aesiv) = RsaAesCipher::generate(key)?;
+                media = media
+                    .with_value_attribute("rsaaeskey".into(), base64::encode(rsaaeskey))
+                    .with_value_attribute("aesiv".into(), base64::encode(aesiv));
+
+                Some(cipher)
+            }
+            None => None,
+        };
+
+        let sdp = SessionDescription {
+            version: 0,
+            origin: Origin {
+                username: "iTunes".into(),
+                session_id: session_id.into(),
+                session_version: 0,
+                network_type: "IN".into(),
+                address_type: address_type(local),
+                unicast_address: local.to_string(),
+            },
+            session_name: "iTunes".into(),
+            connection_information: Some(ConnectionInformation {
+                network_type: "IN".into(),
+                address_type: address_type(remote),
+                address: Some(Address {
+                    address: remote.to_string(),
+                    ttl: None,
+                    range: None,
+                }),
+            }),
+            time_descriptions: vec![TimeDescription {
+                timing: Timing { start_time: 0, stop_time: 0 },
+                repeat_times: Vec::new(),
+            }],
+            media_descriptions: vec![media],
+            ..Default::default()
+        };
+
+        Ok((sdp.marshal(), cipher))
+    }
+}
+
+// same address as the rtsp connection with any port, link local ipv6 needs the scope id to bind
+fn udp_addr(local_addr: SocketAddr) -> SocketAddr {
+    let mut addr = local_addr;
+    addr.set_port(0);
+
+    addr
+}
+
+// `target` is either host:port or name of a receiver on local network
+pub async fn resolve(target: &str) -> Result<SocketAddr> {
+    match target.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => mdns::resolve("_raop._tcp.local.", target).await,
+    }
+}
+
+struct RtspClient {
+    framed: Framed<TcpStream, RtspCodec>,
+    local_ip: IpAddr,
+    session_id: u32,
+    cseq: u32,
+    dacp_id: String,
+    active_remote: String,
+    // from SETUP response
+    session: Option<String>,
+}
+
+impl RtspClient {
+    fn new(stream: TcpStream, local_ip: IpAddr) -> Self {
+        Self {
+            framed: Framed::new(stream, RtspCodec {}),
+            local_ip,
+            session_id: OsRng.next_u32(),
+            cseq: 0,
+            dacp_id: format!("{:016X}", OsRng.next_u64()),
+            active_remote: OsRng.next_u32().to_string(),
+            session: None,
+        }
+    }
+
+    fn url(&self) -> String {
+        match self.local_ip {
+            IpAddr::V4(ip) => format!("rtsp://{ip}/{}", self.session_id),
+            IpAddr::V6(ip) => format!("rtsp://[{ip}]/{}", self.session_id),
+        }
+    }
+
+    // returns response headers, non 200 status is an error
+    async fn request(&mut self, method: &str, path: &str, mut headers: RtspHeaders, content: Vec<u8>) -> Result<RtspHeaders> {
+        self.cseq += 1;
+        headers.append("CSeq", &self.cseq.to_string());
+        headers.append("User-Agent", USER_AGENT);
+        headers.append("DACP-ID", &self.dacp_id);
+        headers.append("Active-Remote", &self.active_remote);
+        if let Some(session) = &self.session {
+            headers.append("Session", session);
+        }
+
+        let request = RtspRequest {
+            method: method.into(),
+            path: path.into(),
+            headers,
+            content,
+        };
+        trace!("{} {} {:?}", request.method, request.path, request.headers);
+        self.framed.send(request).await?;
+
+        loop {
+            match self.framed.next().await.ok_or_else(|| anyhow!("Connection closed during {method}"))?? {
+                RtspMessage::Response { status, headers, content } => {
+                    debug!("{} response {} {:?}", method, status, headers);
+                    if status != 200 {
+                        let reason = String::from_utf8_lossy(&content);
+                        return Err(anyhow!("{method} failed with status {status} {}", reason.trim()));
+                    }
+
+                    return Ok(headers);
+                }
+                RtspMessage::Malformed(err) => return Err(err.into()),
+                _ => trace!("Ignoring message from receiver during {}", method),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ServerPorts {
+    server_port: u16,
+    control_port: u16,
+}
+
+impl ServerPorts {
+    fn parse(transport: &str) -> Result<Self> {
+        let params = transport.split(';').filter_map(|x| x.split_once('=')).collect::<HashMap<_, _>>();
+        let port = |name: &str| -> Result<u16> {
+            params
+                .get(name)
+                .and_then(|x| x.parse().ok())
+                .ok_or_else(|| anyhow!("Missing {name} in transport {transport:?}"))
+        };
+
+        Ok(Self {
+            server_port: port("server_port")?,
+            control_port: port("control_port")?,
+        })
+    }
+}
+
+struct AudioStream {
+    codec: Codec,
+    cipher: Option<RsaAesCipher>,
+    data: UdpSocket,
+    control: UdpSocket,
+    data_addr: SocketAddr,
+    control_addr: SocketAddr,
+    sequence: u16,
+    rtptime: u32,
+    latency: u32,
+}
+
+impl AudioStream {
+    // paced in real time, receiver buffers `latency` frames ahead of playback
+    async fn play<R: AsyncRead + Unpin>(mut self, source: &mut WavReader<R>) -> Result<()> {
+        let start = Instant::now();
+        let mut frames = 0u64;
+        let mut next_sync = 0u64;
+
+        loop {
+            let samples = source.read(FRAMES_PER_PACKET).await?;
+            if samples.is_empty() {
+                break;
+            }
+
+            sleep_until(start + frames_duration(frames)).await;
+
+            let timestamp = self.rtptime.wrapping_add(frames as u32);
+            if frames >= next_sync {
+                self.sync(timestamp, frames == 0).await?;
+                next_sync += SYNC_INTERVAL as u64;
+            }
+
+            let mut payload = self.codec.encode(&samples);
+            if let Some(cipher) = &self.cipher {
+                payload = cipher.encrypt(&payload);
+            }
+            let packet = RtpPacket {
+                payload_type: PAYLOAD_TYPE,
+                marker: frames == 0,
+                sequence: self.sequence,
+                timestamp,
+                payload,
+            };
+            send(&self.data, RtpCodec {}, packet, self.data_addr).await?;
+
+            self.sequence = self.sequence.wrapping_add(1);
+            frames += samples.len() as u64 / 2;
+        }
+
+        // let receiver play out what it buffered before TEARDOWN
+        sleep(frames_duration(self.latency as u64)).await;
+        info!("Played {:.1}s", frames as f64 / SAMPLE_RATE as f64);
+
+        Ok(())
+    }
+
+    // tells receiver which rtp timestamp plays now
+    async fn sync(&self, timestamp: u32, first: bool) -> Result<()> {
+        let (current_time_seconds, current_time_fraction) = split_ntp(ntp_now());
+        let packet = RtpControlPacket {
+            first,
+            timestamp: timestamp.wrapping_sub(self.latency),
+            current_time_seconds,
+            current_time_fraction,
+            next_timestamp: timestamp,
+        };
+
+        send(&self.control, RtpControlCodec {}, packet, self.control_addr).await
+    }
+}
+
+// answers receiver's timing requests so that it can estimate our clock
+async fn serve_timing(socket: &UdpSocket) -> Result<()> {
+    let mut buf = [0; 128];
+    loop {
+        let (length, addr) = socket.recv_from(&mut buf).await?;
+        let receive_time = ntp_now();
+
+        let request = match tokio_util::codec::Decoder::decode(&mut RtpTimingCodec {}, &mut BytesMut::from(&buf[..length])) {
+            Ok(Some(request)) if request.payload_type == PAYLOAD_TYPE_TIMING_REQUEST => request,
+            _ => {
+                trace!("Ignoring timing packet from {}", addr);
+                continue;
+            }
+        };
+
+        let response = RtpTimingPacket {
+            payload_type: PAYLOAD_TYPE_TIMING_RESPONSE,
+            reference_time: request.send_time,
+            receive_time,
+            send_time: ntp_now(),
+        };
+        send(socket, RtpTimingCodec {}, response, addr).await?;
+    }
+}
+
+async fn send<C: Encoder<T>, T>(socket: &UdpSocket, mut codec: C, packet: T, addr: SocketAddr) -> Result<()>
+where
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut data = BytesMut::new();
+    codec.encode(packet, &mut data)?;
+    socket.send_to(&data, addr).await?;
+
+    Ok(())
+}
+
+fn frames_duration(frames: u64) -> Duration {
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+// 32.32 fixed point seconds since 1900
+fn ntp_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    ((now.as_secs() + NTP_EPOCH_OFFSET) << 32) | fraction
+}
+
+fn split_ntp(time: u64) -> (u32, u32) {
+    ((time >> 32) as u32, time as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use std::net::SocketAddrV6;
+
+    #[tokio::test]
+    async fn test_server_ports() -> Result<()> {
+        let ports = ServerPorts::parse("RTP/AVP/UDP;unicast;mode=record;server_port=6000;control_port=6001;timing_port=6002")?;
+        assert_eq!((ports.server_port, ports.control_port), (6000, 6001));
+
+        assert!(ServerPorts::parse("RTP/AVP/TCP;unicast;mode=record;interleaved=0-1").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sdp() -> Result<()> {
+        let sender = Sender::builder().encrypt(true).build();
+        let (sdp, cipher) = sender.sdp("10.0.0.2".parse()?, "10.0.0.1".parse()?, 1)?;
+
+        // what receiver's ANNOUNCE handling expects
+        let description = SessionDescription::unmarshal(&mut std::io::Cursor::new(sdp.as_bytes()))?;
+        assert_eq!(description.get_codec_for_payload_type(96)?.name, "AppleLossless");
+        let media = &description.media_descriptions[0];
+        let rsaaeskey = base64::decode(media.attribute("rsaaeskey").flatten().unwrap())?;
+        let aesiv = base64::decode(media.attribute("aesiv").flatten().unwrap())?;
+
+        let receiver = RsaAesCipher::new(&KEY, &rsaaeskey, &aesiv)?;
+        assert_eq!(receiver.decrypt(&cipher.unwrap().encrypt(&[1; 20]))?, [1; 20]);
+
+        let sender = Sender::builder().codec(Codec::L16).build();
+        let (sdp, cipher) = sender.sdp("10.0.0.2".parse()?, "fe80::1".parse()?, 1)?;
+        assert!(cipher.is_none());
+        assert_eq!(
+            sdp,
+            "v=0\r\no=iTunes 1 0 IN IP4 10.0.0.2\r\ns=iTunes\r\nc=IN IP6 fe80::1\r\nt=0 0\r\nm=audio 0 RTP/AVP 96\r\na=rtpmap:96 L16/44100/2\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_udp_addr() -> Result<()> {
+        assert_eq!(udp_addr("10.0.0.2:5000".parse()?), "10.0.0.2:0".parse()?);
+
+        // link local connection keeps its interface
+        let local_addr = SocketAddr::V6(SocketAddrV6::new("fe80::2".parse()?, 5000, 0, 3));
+        assert_eq!(udp_addr(local_addr), SocketAddr::V6(SocketAddrV6::new("fe80::2".parse()?, 0, 0, 3)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ntp() -> Result<()> {
+        let (seconds, _) = split_ntp(ntp_now());
+        assert!(seconds as u64 > NTP_EPOCH_OFFSET);
+        assert_eq!(split_ntp((5 << 32) | (1 << 31)), (5, 1 << 31));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, BufReader},
+};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// 16 bit pcm wav, which is what we can stream without a resampler or decoder
+pub struct WavReader<R> {
+    reader: R,
+    channels: u16,
+    // bytes left in data chunk, None if the writer didn't know the length
+    remaining: Option<u64>,
+}
+
+impl WavReader<BufReader<File>> {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).await.with_context(|| format!("Can't open {}", path.display()))?;
+
+        Self::new(BufReader::new(file))
+            .await
+            .with_context(|| format!("Can't read {}", path.display()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> WavReader<R> {
+    pub async fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header).await?;
+        if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+            return Err(anyhow!("Not a wav file"));
+        }
+
+        let mut channels = None;
+        loop {
+            let mut chunk = [0; 8];
+            reader.read_exact(&mut chunk).await?;
+            let length = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+            match &chunk[..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0; length as usize + length as usize % 2];
+                    reader.read_exact(&mut fmt).await?;
+                    if fmt.len() < 16 {
+                        return Err(anyhow!("Truncated fmt chunk"));
+                    }
+                    let read_u16 = |offset: usize| u16::from_le_bytes([fmt[offset], fmt[offset + 1]]);
+
+                    let format = read_u16(0);
+                    let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let bits = read_u16(14);
+                    if !matches!(format, FORMAT_PCM | FORMAT_EXTENSIBLE) || bits != 16 {
+                        return Err(anyhow!("Unsupported wav format {format} with {bits} bits, expected 16 bit pcm"));
+                    }
+                    if rate != 44100 {
+                        return Err(anyhow!("Unsupported sample rate {rate}, expected 44100"));
+                    }
+                    channels = match read_u16(2) {
+                        channels @ (1 | 2) => Some(channels),
+                        channels => return Err(anyhow!("Unsupported channel count {channels}")),
+                    };
+                }
+                b"data" => {
+                    let channels = channels.ok_or_else(|| anyhow!("Missing fmt chunk"))?;
+                    // streaming writers leave length at 0 or max
+                    let remaining = (length != 0 && length != u32::MAX).then_some(length as u64);
+
+                    return Ok(Self { reader, channels, remaining });
+                }
+                _ => {
+                    let skip = length as u64 + length as u64 % 2;
+                    tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+                }
+            }
+        }
+    }
+
+    // interleaved stereo samples of up to `frames` frames, empty at the end
+    pub async fn read(&mut self, frames: usize) -> Result<Vec<i16>> {
+        let mut length = frames as u64 * self.channels as u64 * 2;
+        if let Some(remaining) = self.remaining {
+            length = length.min(remaining);
+        }
+
+        let mut data = Vec::with_capacity(length as usize);
+        (&mut self.reader).take(length).read_to_end(&mut data).await?;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= data.len() as u64;
+        }
+
+        // drops trailing partial frame of a truncated file
+        let frame_size = self.channels as usize * 2;
+        data.truncate(data.len() / frame_size * frame_size);
+
+        let samples = data.chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]]));
+        Ok(match self.channels {
+            1 => samples.flat_map(|x| [x, x]).collect(),
+            _ => samples.collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    fn wav(channels: u16, samples: &[i16]) -> Vec<u8> {
+        let data = samples.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend_from_slice(b"fmt \x10\0\0\0\x01\0");
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100 * 2 * channels as u32).to_le_bytes());
+        wav.extend_from_slice(&(2 * channels).to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        // unknown chunks are skipped, with padding of odd length
+        wav.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        // trailing chunk isn't audio
+        wav.extend_from_slice(b"id3 \x02\0\0\0ab");
+
+        wav
+    }
+
+    #[tokio::test]
+    async fn test_wav() -> Result<()> {
+        let data = wav(2, &[1, 2, 3, 4, 5, 6]);
+        let mut reader = WavReader::new(data.as_slice()).await?;
+
+        assert_eq!(reader.read(2).await?, [1, 2, 3, 4]);
+        assert_eq!(reader.read(2).await?, [5, 6]);
+        assert!(reader.read(2).await?.is_empty());
+
+        // mono is duplicated to both channels
+        let data = wav(1, &[1, 2]);
+        let mut reader = WavReader::new(data.as_slice()).await?;
+        assert_eq!(reader.read(352).await?, [1, 1, 2, 2]);
+
+        let mut data = wav(2, &[1, 2]);
+        data[24..28].copy_from_slice(&48000u32.to_le_bytes());
+        assert!(WavReader::new(data.as_slice()).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error, Result};
+use log::info;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+// what to do when a sender connects while another one is playing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionPolicy {
+    // newcomer gets 453 Not Enough Bandwidth
+    Reject,
+    // newcomer takes over, playing session is torn down
+    Preempt,
+    // every sender plays at once
+    #[default]
+    Mix,
+}
+
+impl FromStr for SessionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "preempt" => Ok(Self::Preempt),
+            "mix" => Ok(Self::Mix),
+            _ => Err(anyhow!("Unknown session policy {s:?}, expected reject, preempt or mix")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub addr: SocketAddr,
+    pub client_name: Option<String>,
+    pub dacp_id: Option<String>,
+    pub active_remote: Option<String>,
+}
+
+impl SessionInfo {
+    pub fn display_name(&self) -> String {
+        self.client_name.clone().unwrap_or_else(|| self.addr.ip().to_string())
+    }
+}
+
+struct ActiveSession {
+    info: SessionInfo,
+    stop: Arc<Notify>,
+}
+
+// sessions which announced a stream, i.e. using the speaker
+pub struct Sessions {
+    policy: SessionPolicy,
+    active: Mutex<HashMap<u32, ActiveSession>>,
+}
+
+impl Sessions {
+    pub fn new(policy: SessionPolicy) -> Self {
+        Self {
+            policy,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // on failure, returns name of the client using the speaker
+    pub fn acquire(&self, id: u32, info: SessionInfo, stop: Arc<Notify>) -> Result<(), String> {
+        let mut active = self.active.lock().unwrap();
+
+        let others = active
+            .iter()
+            .filter(|(other_id, _)| **other_id != id)
+            .map(|(other_id, other)| (*other_id, other))
+            .collect::<Vec<_>>();
+
+        // reconnecting sender replaces its own stale session regardless of policy
+        let same_sender = |other: &ActiveSession| info.dacp_id.is_some() && other.info.dacp_id == info.dacp_id;
+
+        let mut preempted = Vec::new();
+        for (other_id, other) in others {
+            if same_sender(other) || self.policy == SessionPolicy::Preempt {
+                preempted.push(other_id);
+            } else if self.policy == SessionPolicy::Reject {
+                return Err(other.info.display_name());
+            }
+        }
+
+        for other_id in preempted {
+            let other = active.remove(&other_id).unwrap();
+            info!("Session {} preempted by session {} from {}", other_id, id, info.display_name());
+
+            other.stop.notify_one();
+        }
+
+        active.insert(id, ActiveSession { info, stop });
+
+        Ok(())
+    }
+
+    pub fn release(&self, id: u32) {
+        self.active.lock().unwrap().remove(&id);
+    }
+
+    // without id, returns the most recent session
+    pub fn find(&self, id: Option<u32>) -> Option<SessionInfo> {
+        let active = self.active.lock().unwrap();
+
+        let id = id.or_else(|| active.keys().max().copied())?;
+        active.get(&id).map(|x| x.info.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    fn info(client_name: &str, dacp_id: &str) -> SessionInfo {
+        SessionInfo {
+            addr: "127.0.0.1:1234".parse().unwrap(),
+            client_name: Some(client_name.into()),
+            dacp_id: Some(dacp_id.into()),
+            active_remote: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject() -> Result<()> {
+        let sessions = Sessions::new(SessionPolicy::Reject);
+        let (stop1, stop2) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+
+        assert!(sessions.acquire(1, info("phone", "1"), stop1.clone()).is_ok());
+        assert_eq!(sessions.acquire(2, info("laptop", "2"), stop2.clone()), Err("phone".into()));
+
+        // same sender reconnects
+        assert!(sessions.acquire(3, info("phone", "1"), stop2.clone()).is_ok());
+        stop1.notified().await;
+
+        sessions.release(3);
+        assert!(sessions.acquire(2, info("laptop", "2"), stop2).is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preempt() -> Result<()> {
+        let sessions = Sessions::new(SessionPolicy::Preempt);
+        let (stop1, stop2) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+
+        assert!(sessions.acquire(1, info("phone", "1"), stop1.clone()).is_ok());
+        assert!(sessions.acquire(2, info("laptop", "2"), stop2).is_ok());
+        stop1.notified().await;
+
+        assert_eq!(sessions.active.lock().unwrap().keys().copied().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(sessions.find(None).and_then(|x| x.client_name), Some("laptop".into()));
+        assert!(sessions.find(Some(1)).is_none());
+
+        Ok(())
+    }
+}
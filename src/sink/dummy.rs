@@ -1,10 +1,11 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use anyhow::Result;
 use log::trace;
 
 use super::{AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::Result;
 
+#[derive(Default)]
 pub struct DummyAudioSink {}
 
 impl DummyAudioSink {
@@ -14,8 +15,8 @@ impl DummyAudioSink {
 }
 
 impl AudioSink for DummyAudioSink {
-    fn start(&self) -> Result<Rc<dyn AudioSinkSession>> {
-        Ok(Rc::new(DummyAudioSinkSession {}))
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        Ok(Arc::new(DummyAudioSinkSession {}))
     }
 }
 
@@ -28,7 +29,7 @@ impl AudioSinkSession for DummyAudioSinkSession {
         Ok(())
     }
 
-    fn set_volume(&self, volume: f32) {
-        trace!("DummyAudioSink::set_volume {:?}", volume);
+    fn set_volume(&self, gain: f32) {
+        trace!("DummyAudioSink::set_volume {:?}", gain);
     }
 }
@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex};
+
+use super::{apply_gain, AudioFormat, AudioSinkSession};
+use crate::error::Result;
+
+// full scale volume change takes this long, smaller ones are quicker
+const VOLUME_RAMP_SECONDS: f32 = 0.02;
+// fade in and out length, we hold back this much audio to fade it out before it's gone
+const FADE_SECONDS: f32 = 0.01;
+
+struct GainState {
+    volume: f32,
+    target_volume: f32,
+    // fade envelope, 0.0 ~ 1.0
+    fade: f32,
+    // interleaved samples held back for fade out
+    pending: Vec<i16>,
+    channels: u8,
+    rate: u32,
+}
+
+// applies volume and fades in front of a sink session, which is kept at unity gain
+pub(crate) struct GainStage {
+    inner: Arc<dyn AudioSinkSession>,
+    state: Mutex<GainState>,
+}
+
+impl GainStage {
+    pub fn new(inner: Arc<dyn AudioSinkSession>, volume: f32) -> Self {
+        inner.set_volume(1.0);
+
+        Self {
+            inner,
+            state: Mutex::new(GainState {
+                volume,
+                target_volume: volume,
+                // every stream starts with fade in
+                fade: 0.0,
+                pending: Vec::new(),
+                channels: 2,
+                rate: 44100,
+            }),
+        }
+    }
+
+    pub fn set_volume(&self, gain: f32) {
+        self.state.lock().unwrap().target_volume = gain;
+    }
+
+    pub fn write(&self, payload: &[u8], channels: u8, rate: u32, format: AudioFormat) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if (channels, rate) != (state.channels, state.rate) {
+            // held back samples are in the old format
+            drop(state);
+            self.fade_out()?;
+            state = self.state.lock().unwrap();
+
+            state.channels = channels;
+            state.rate = rate;
+        }
+
+        state.pending.extend(payload.chunks_exact(2).map(|x| match format {
+            AudioFormat::S16BE => i16::from_be_bytes([x[0], x[1]]),
+            AudioFormat::S16NE => i16::from_ne_bytes([x[0], x[1]]),
+        }));
+
+        let lookahead = (state.rate as f32 * FADE_SECONDS) as usize * state.channels as usize;
+        if state.pending.len() <= lookahead {
+            return Ok(());
+        }
+
+        let ready = state.pending.len() - lookahead;
+        let mut samples = state.pending.drain(..ready).collect::<Vec<_>>();
+        state.process(&mut samples, 1.0);
+        drop(state);
+
+        self.write_samples(&samples)
+    }
+
+    // writes held back audio fading out to silence, next audio fades in
+    pub fn fade_out(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut samples = std::mem::take(&mut state.pending);
+        state.process(&mut samples, 0.0);
+        state.fade = 0.0;
+        drop(state);
+
+        self.write_samples(&samples)
+    }
+
+    fn write_samples(&self, samples: &[i16]) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let state = self.state.lock().unwrap();
+        let payload = samples.iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>();
+
+        self.inner.write(&payload, state.channels, state.rate, AudioFormat::S16NE)
+    }
+}
+
+impl GainState {
+    // ramps fade envelope towards target over given samples, and volume towards target volume
+    fn process(&mut self, samples: &mut [i16], fade_target: f32) {
+        let channels = self.channels.max(1) as usize;
+        let frames = samples.len() / channels;
+
+        let volume_step = 1.0 / (self.rate as f32 * VOLUME_RAMP_SECONDS);
+        let fade_step = if fade_target < self.fade {
+            // fade out has to reach silence by the end of what's left
+            self.fade / frames.max(1) as f32
+        } else {
+            1.0 / (self.rate as f32 * FADE_SECONDS)
+        };
+
+        for frame in samples.chunks_mut(channels) {
+            self.volume = step_towards(self.volume, self.target_volume, volume_step);
+            self.fade = step_towards(self.fade, fade_target, fade_step);
+
+            let gain = self.volume * self.fade;
+            for sample in frame {
+                *sample = apply_gain(gain, *sample);
+            }
+        }
+    }
+}
+
+fn step_towards(current: f32, target: f32, step: f32) -> f32 {
+    if current < target {
+        (current + step).min(target)
+    } else {
+        (current - step).max(target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sink::RecordingSession;
+    use anyhow::Result;
+
+    fn payload(frames: usize) -> Vec<u8> {
+        vec![10000i16; frames].iter().flat_map(|x| x.to_ne_bytes()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_fade() -> Result<()> {
+        let session = Arc::new(RecordingSession::default());
+        let gain = GainStage::new(session.clone(), 1.0);
+
+        // 10ms at 1000Hz mono is 10 frames held back
+        gain.write(&payload(10), 1, 1000, AudioFormat::S16NE)?;
+        assert!(session.samples.lock().unwrap().is_empty());
+
+        gain.write(&payload(20), 1, 1000, AudioFormat::S16NE)?;
+        {
+            let samples = session.samples.lock().unwrap();
+            assert_eq!(samples.len(), 20);
+            // fades in over 10 frames
+            assert!(samples[0] < 2000);
+            assert!(samples.windows(2).take(9).all(|x| x[0] < x[1]));
+            assert!(samples[10..].iter().all(|x| *x == 10000));
+        }
+
+        gain.fade_out()?;
+        {
+            let samples = session.samples.lock().unwrap();
+            assert_eq!(samples.len(), 30);
+            assert!(samples[20..].windows(2).all(|x| x[0] > x[1]));
+            assert_eq!(samples[29], 0);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_volume_ramp() -> Result<()> {
+        let session = Arc::new(RecordingSession::default());
+        let gain = GainStage::new(session.clone(), 1.0);
+
+        gain.write(&payload(100), 1, 1000, AudioFormat::S16NE)?;
+        gain.set_volume(0.0);
+        gain.write(&payload(100), 1, 1000, AudioFormat::S16NE)?;
+
+        let samples = session.samples.lock().unwrap();
+        // 20ms ramp to silence at 1000Hz
+        assert_eq!(samples[89], 10000);
+        assert!(samples[90] < 10000 && samples[90] > 9000);
+        assert_eq!(samples[110], 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+
+use super::{apply_gain, AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::{Error, Result};
+
+// mixed samples above this level are compressed instead of clipped
+const SOFT_CLIP_KNEE: f32 = 0.8;
+
+struct Source {
+    // interleaved samples in mixer's channel count
+    queue: VecDeque<i16>,
+    // has audio in the mix, from then on the output thread writes it on every tick
+    joined: bool,
+    // format doesn't match the mix, its audio is dropped
+    muted: bool,
+}
+
+struct MixerState {
+    sources: HashMap<u64, Source>,
+    next_id: u64,
+    channels: u8,
+    rate: u32,
+    // session of the inner sink, opened with the first session
+    output: Option<Arc<dyn AudioSinkSession>>,
+}
+
+// sums every session into one stream of the inner sink.
+// has no clock of its own, it's meant to be wrapped in OutputAudioSink which writes each session on a fixed period
+pub struct MixerAudioSink {
+    inner: Arc<dyn AudioSink>,
+    state: Arc<Mutex<MixerState>>,
+}
+
+impl MixerAudioSink {
+    pub fn new(inner: Arc<dyn AudioSink>) -> Self {
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(MixerState {
+                sources: HashMap::new(),
+                next_id: 0,
+                channels: 2,
+                rate: 44100,
+                output: None,
+            })),
+        }
+    }
+}
+
+impl AudioSink for MixerAudioSink {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        let mut state = self.state.lock().unwrap();
+        if state.output.is_none() {
+            state.output = Some(self.inner.start()?);
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sources.insert(
+            id,
+            Source {
+                queue: VecDeque::new(),
+                joined: false,
+                muted: false,
+            },
+        );
+
+        Ok(Arc::new(MixerAudioSinkSession {
+            id,
+            state: self.state.clone(),
+        }))
+    }
+
+    // sessions are gone by now, so output is closed before the inner sink flushes
+    fn flush(&self) -> Result<()> {
+        self.state.lock().unwrap().output = None;
+
+        self.inner.flush()
+    }
+}
+
+pub struct MixerAudioSinkSession {
+    id: u64,
+    state: Arc<Mutex<MixerState>>,
+}
+
+impl Drop for MixerAudioSinkSession {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().sources.remove(&self.id);
+    }
+}
+
+impl AudioSinkSession for MixerAudioSinkSession {
+    fn write(&self, payload: &[u8], channels: u8, rate: u32, format: AudioFormat) -> Result<()> {
+        let samples = payload
+            .chunks_exact(2)
+            .map(|x| match format {
+                AudioFormat::S16BE => i16::from_be_bytes([x[0], x[1]]),
+                AudioFormat::S16NE => i16::from_ne_bytes([x[0], x[1]]),
+            })
+            .collect::<Vec<_>>();
+
+        let mut state = self.state.lock().unwrap();
+        state.push(self.id, &samples, channels, rate)?;
+
+        match (state.mix(), &state.output) {
+            (Some(mixed), Some(output)) => {
+                let payload = mixed.iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>();
+                output.write(&payload, state.channels, state.rate, AudioFormat::S16NE)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // sessions' volume is applied before they're mixed
+    fn set_volume(&self, _: f32) {}
+}
+
+impl MixerState {
+    fn push(&mut self, id: u64, samples: &[i16], channels: u8, rate: u32) -> Result<()> {
+        // output format follows the session while no other one is mixed, we don't resample
+        if self.sources.iter().all(|(x, source)| *x == id || !source.joined) {
+            self.channels = channels;
+            self.rate = rate;
+        }
+
+        let (mixer_channels, mixer_rate) = (self.channels, self.rate);
+        let source = self.sources.get_mut(&id).ok_or_else(|| Error::Sink("Unknown mixer session".into()))?;
+
+        let converted = match (channels, mixer_channels) {
+            _ if rate != mixer_rate => None,
+            (x, y) if x == y => Some(samples.to_vec()),
+            (1, 2) => Some(samples.iter().flat_map(|x| [*x, *x]).collect()),
+            (2, 1) => Some(samples.chunks_exact(2).map(|x| ((x[0] as i32 + x[1] as i32) / 2) as i16).collect()),
+            _ => None,
+        };
+
+        match converted {
+            Some(samples) => {
+                source.queue.extend(samples);
+                source.joined = true;
+                source.muted = false;
+            }
+            None => {
+                if !source.muted {
+                    warn!(
+                        "Mixer can't mix {} channels at {}Hz into {} channels at {}Hz, session is muted",
+                        channels, rate, mixer_channels, mixer_rate
+                    );
+                }
+                source.queue.clear();
+                source.joined = false;
+                source.muted = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // mixes as much as every joined session has, each one gets the same amount on every tick of the output thread
+    fn mix(&mut self) -> Option<Vec<i16>> {
+        let joined = self.sources.values_mut().filter(|x| x.joined).collect::<Vec<_>>();
+        let length = joined.iter().map(|x| x.queue.len()).min().filter(|x| *x > 0)?;
+
+        let mut mixed = vec![0f32; length];
+        for source in joined {
+            for (mixed, sample) in mixed.iter_mut().zip(source.queue.drain(..length)) {
+                *mixed += sample as f32;
+            }
+        }
+
+        Some(mixed.into_iter().map(soft_clip).collect())
+    }
+}
+
+fn soft_clip(sample: f32) -> i16 {
+    let x = sample / i16::MAX as f32;
+    let magnitude = x.abs();
+
+    let clipped = if magnitude <= SOFT_CLIP_KNEE {
+        magnitude
+    } else {
+        // approaches full scale smoothly, never exceeds it
+        SOFT_CLIP_KNEE + (1.0 - SOFT_CLIP_KNEE) * ((magnitude - SOFT_CLIP_KNEE) / (1.0 - SOFT_CLIP_KNEE)).tanh()
+    };
+
+    apply_gain(clipped.copysign(x), i16::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sink::RecordingSink;
+    use anyhow::Result;
+
+    fn samples(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|x| x.to_ne_bytes()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_mix() -> Result<()> {
+        let inner = Arc::new(RecordingSink::default());
+        let mixer = MixerAudioSink::new(inner.clone());
+        let first = mixer.start()?;
+        let second = mixer.start()?;
+        assert_eq!(inner.starts.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // session alone in the mix is written through, with its prebuffer lead
+        first.write(&samples(&[1000; 50]), 1, 1000, AudioFormat::S16NE)?;
+        assert_eq!(*inner.samples.lock().unwrap(), [1000; 50]);
+
+        // once both are in, what they have in common is mixed
+        second.write(&samples(&[2000; 50]), 1, 1000, AudioFormat::S16NE)?;
+        first.write(&samples(&[1000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        assert_eq!(inner.samples.lock().unwrap()[50..], [3000; 10]);
+        second.write(&samples(&[2000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        first.write(&samples(&[1000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        assert_eq!(inner.samples.lock().unwrap().len(), 70);
+
+        // rest of the other one goes on alone
+        drop(first);
+        second.write(&samples(&[2000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        assert_eq!(inner.samples.lock().unwrap()[70..], [2000; 50]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mix_format() -> Result<()> {
+        let mixer = MixerAudioSink::new(Arc::new(RecordingSink::default()));
+        let first = mixer.start()?;
+        let second = mixer.start()?;
+
+        // first session decides output format, later ones are converted into it
+        first.write(&samples(&[1000; 2]), 1, 1000, AudioFormat::S16NE)?;
+        second.write(&samples(&[1000, 3000]), 2, 1000, AudioFormat::S16NE)?;
+        {
+            let state = mixer.state.lock().unwrap();
+            assert_eq!(state.sources[&1].queue, [2000]);
+        }
+
+        // other rate can't be mixed
+        second.write(&samples(&[0; 2]), 1, 48000, AudioFormat::S16NE)?;
+        {
+            let state = mixer.state.lock().unwrap();
+            assert!(state.sources[&1].muted && !state.sources[&1].joined);
+        }
+
+        // mixer follows the session left alone
+        drop(first);
+        second.write(&samples(&[1000, 3000]), 2, 48000, AudioFormat::S16NE)?;
+        let state = mixer.state.lock().unwrap();
+        assert_eq!((state.channels, state.rate), (2, 48000));
+        assert!(!state.sources[&1].muted);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_clip() -> Result<()> {
+        assert_eq!(soft_clip(1000.0), 1000);
+        assert_eq!(soft_clip(-1000.0), -1000);
+        assert!(soft_clip(30000.0) < 30000);
+        assert!(soft_clip(60000.0) > soft_clip(40000.0));
+        assert_eq!(soft_clip(1e9), i16::MAX);
+        assert_eq!(soft_clip(-1e9), -i16::MAX);
+
+        Ok(())
+    }
+}
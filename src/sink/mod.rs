@@ -1,9 +1,30 @@
 mod dummy;
+mod gain;
+mod mixer;
+mod output;
+#[cfg(test)]
+mod recording;
 mod rodio;
+mod rtp;
+mod tcp;
+mod volume;
 
-use std::rc::Rc;
+use std::{net::SocketAddr, sync::Arc};
 
-use anyhow::Result;
+use crate::error::{Error, Result};
+
+#[cfg(test)]
+pub(crate) use self::recording::{RecordingSession, RecordingSink};
+pub use self::{
+    dummy::DummyAudioSink,
+    mixer::MixerAudioSink,
+    output::OutputAudioSink,
+    rodio::RodioAudioSink,
+    rtp::{MulticastInterface, RtpAudioSink},
+    tcp::TcpAudioSink,
+    volume::VolumeCurve,
+};
+pub(crate) use self::{gain::GainStage, volume::apply_gain};
 
 #[derive(Copy, Clone)]
 pub enum AudioFormat {
@@ -11,19 +32,39 @@ pub enum AudioFormat {
     S16NE,
 }
 
-pub trait AudioSink {
-    fn start(&self) -> Result<Rc<dyn AudioSinkSession>>;
+pub trait AudioSink: Send + Sync {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>>;
+    // called on shutdown after every session ended, sinks buffering output should write it out here
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub trait AudioSinkSession: Send + Sync {
     fn write(&self, payload: &[u8], channels: u8, rate: u32, format: AudioFormat) -> Result<()>;
-    fn set_volume(&self, volume: f32);
+    // sessions started by a receiver always get 1.0, as sender volume is applied to the audio before write
+    fn set_volume(&self, gain: f32);
 }
 
-pub fn create(sink: &str) -> Rc<dyn AudioSink> {
-    match sink {
-        "dummy" => Rc::new(dummy::DummyAudioSink::new()),
-        "rodio" => Rc::new(rodio::RodioAudioSink::new()),
-        _ => panic!("Unknown sink"),
-    }
+// network sinks take their address after a colon, e.g. `rtp:239.255.0.1:5004` or `tcp:0.0.0.0:5000`
+// sinks are written from their own output thread
+pub fn create(sink: &str) -> Result<Arc<dyn AudioSink>> {
+    Ok(Arc::new(OutputAudioSink::new(open(sink)?)?))
+}
+
+// sink without output thread
+pub(crate) fn open(sink: &str) -> Result<Arc<dyn AudioSink>> {
+    let (name, addr) = sink.split_once(':').unwrap_or((sink, ""));
+
+    Ok(match name {
+        "dummy" => Arc::new(DummyAudioSink::new()),
+        "rodio" => Arc::new(RodioAudioSink::new()?),
+        "rtp" => Arc::new(RtpAudioSink::new(parse_addr(addr)?)?),
+        "tcp" => Arc::new(TcpAudioSink::new(parse_addr(addr)?)?),
+        _ => return Err(Error::Sink(format!("Unknown sink {sink:?}"))),
+    })
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse().map_err(|_| Error::Sink(format!("Invalid sink address {addr:?}")))
 }
@@ -0,0 +1,362 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use super::{AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::{Error, Result};
+
+// output thread writes to the inner sink this often
+const TICK: Duration = Duration::from_millis(10);
+// ring buffer of each session, 2s of 48kHz stereo
+const QUEUE_SAMPLES: usize = 48000 * 2 * 2;
+// a session starts playing once it has this much audio, which is written ahead so that late ticks don't starve the inner sink
+const PREBUFFER: Duration = Duration::from_millis(50);
+
+// format of the queued audio and overruns, shared by the session and the output thread
+struct StreamFormat {
+    channels: AtomicU8,
+    rate: AtomicU32,
+    overruns: AtomicU64,
+}
+
+struct Stream {
+    consumer: Consumer<i16>,
+    format: Arc<StreamFormat>,
+    output: Arc<dyn AudioSinkSession>,
+    // when playback started and frames written since then, None while prebuffering
+    clock: Option<(Instant, u64)>,
+    underruns: u64,
+    // queue ran dry, silence is written in place of audio
+    starved: bool,
+    // sink is flushing, the rest of the queue is played out even if the session is still alive
+    closing: bool,
+}
+
+// writes audio of every session to the inner sink from a dedicated thread on a fixed period,
+// so sink timing doesn't depend on when packets arrive
+pub struct OutputAudioSink {
+    inner: Arc<dyn AudioSink>,
+    streams: Mutex<Option<mpsc::Sender<Stream>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl OutputAudioSink {
+    pub fn new(inner: Arc<dyn AudioSink>) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new().name("audio output".into()).spawn(move || Self::run(receiver))?;
+
+        Ok(Self {
+            inner,
+            streams: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    fn run(receiver: mpsc::Receiver<Stream>) {
+        let mut streams = Vec::<Stream>::new();
+        let mut closing = false;
+        let mut next_tick = Instant::now();
+
+        loop {
+            if streams.is_empty() {
+                if closing {
+                    return;
+                }
+                // nothing to play, sleep until a session starts
+                match receiver.recv() {
+                    Ok(stream) => streams.push(stream),
+                    Err(_) => return,
+                }
+                next_tick = Instant::now();
+            }
+
+            loop {
+                match receiver.try_recv() {
+                    Ok(stream) => streams.push(stream),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        closing = true;
+                        break;
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            streams.retain_mut(|stream| {
+                stream.closing |= closing;
+                stream.play(now)
+            });
+
+            next_tick += TICK;
+            match next_tick.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                // we're late, don't try to catch up with a burst of ticks
+                None => next_tick = Instant::now(),
+            }
+        }
+    }
+}
+
+impl Drop for OutputAudioSink {
+    fn drop(&mut self) {
+        // thread exits by itself once queued audio is played
+        self.streams.lock().unwrap().take();
+    }
+}
+
+impl AudioSink for OutputAudioSink {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        let output = self.inner.start()?;
+        let (producer, consumer) = RingBuffer::new(QUEUE_SAMPLES);
+        let format = Arc::new(StreamFormat {
+            channels: AtomicU8::new(2),
+            rate: AtomicU32::new(44100),
+            overruns: AtomicU64::new(0),
+        });
+
+        let stream = Stream {
+            consumer,
+            format: format.clone(),
+            output: output.clone(),
+            clock: None,
+            underruns: 0,
+            starved: false,
+            closing: false,
+        };
+        self.streams
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|x| x.send(stream).ok())
+            .ok_or_else(|| Error::Sink("Audio output thread stopped".into()))?;
+
+        Ok(Arc::new(OutputAudioSinkSession {
+            producer: Mutex::new(producer),
+            format,
+            output,
+        }))
+    }
+
+    // plays out what's queued before the inner sink flushes
+    fn flush(&self) -> Result<()> {
+        self.streams.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().map_err(|_| Error::Sink("Audio output thread panicked".into()))?;
+        }
+
+        self.inner.flush()
+    }
+}
+
+pub struct OutputAudioSinkSession {
+    // only the session's audio task writes, the lock is never contended
+    producer: Mutex<Producer<i16>>,
+    format: Arc<StreamFormat>,
+    output: Arc<dyn AudioSinkSession>,
+}
+
+impl AudioSinkSession for OutputAudioSinkSession {
+    fn write(&self, payload: &[u8], channels: u8, rate: u32, format: AudioFormat) -> Result<()> {
+        self.format.channels.store(channels, Ordering::Relaxed);
+        self.format.rate.store(rate, Ordering::Relaxed);
+
+        let samples = payload.chunks_exact(2).map(|x| match format {
+            AudioFormat::S16BE => i16::from_be_bytes([x[0], x[1]]),
+            AudioFormat::S16NE => i16::from_ne_bytes([x[0], x[1]]),
+        });
+
+        let mut producer = self.producer.lock().unwrap();
+        let length = samples.len().min(producer.slots());
+        let written = producer
+            .write_chunk_uninit(length)
+            .map_err(|err| Error::Sink(err.to_string()))?
+            .fill_from_iter(samples);
+
+        // sender is further ahead than we can queue, newest audio is dropped
+        if written < payload.len() / 2 {
+            self.format.overruns.fetch_add(1, Ordering::Relaxed);
+            debug!("Audio output queue is full, dropping {} samples", payload.len() / 2 - written);
+        }
+
+        Ok(())
+    }
+
+    fn set_volume(&self, gain: f32) {
+        self.output.set_volume(gain);
+    }
+}
+
+impl Stream {
+    // writes audio due by now to the inner sink, returns false when the stream is done
+    fn play(&mut self, now: Instant) -> bool {
+        let channels = self.format.channels.load(Ordering::Relaxed) as usize;
+        let rate = self.format.rate.load(Ordering::Relaxed);
+        let prebuffer = (rate as f64 * PREBUFFER.as_secs_f64()) as u64;
+        let ended = self.closing || self.consumer.is_abandoned();
+        let available = self.consumer.slots();
+
+        let (start, written) = match self.clock {
+            Some(clock) => clock,
+            None if available >= prebuffer as usize * channels || (ended && available > 0) => (now, 0),
+            None if ended => {
+                self.report();
+                return false;
+            }
+            None => return true,
+        };
+
+        // prebuffered audio is written right away as lead
+        let due = ((now.duration_since(start).as_secs_f64() * rate as f64) as u64 + prebuffer).saturating_sub(written);
+        self.clock = Some((start, written + due));
+
+        let wanted = due as usize * channels;
+        let length = wanted.min(available);
+        let mut samples = Vec::with_capacity(wanted);
+        if let Ok(chunk) = self.consumer.read_chunk(length) {
+            samples.extend(chunk);
+        }
+
+        if length < wanted {
+            if ended {
+                self.report();
+                self.write(&samples, channels as u8, rate);
+                return false;
+            }
+
+            // underrun, keep the clock going with silence until audio is back
+            if !self.starved {
+                self.underruns += 1;
+                self.starved = true;
+                debug!("Audio output underrun");
+            }
+            samples.resize(wanted, 0);
+        } else {
+            self.starved = false;
+        }
+
+        self.write(&samples, channels as u8, rate);
+
+        true
+    }
+
+    fn write(&self, samples: &[i16], channels: u8, rate: u32) {
+        if samples.is_empty() {
+            return;
+        }
+
+        let payload = samples.iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>();
+        if let Err(err) = self.output.write(&payload, channels, rate, AudioFormat::S16NE) {
+            warn!("Audio output failed: {:?}", err);
+        }
+    }
+
+    fn report(&self) {
+        let overruns = self.format.overruns.load(Ordering::Relaxed);
+        if self.underruns > 0 || overruns > 0 {
+            warn!("Audio output had {} underruns and {} overruns", self.underruns, overruns);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sink::RecordingSession;
+    use anyhow::Result;
+
+    fn stream() -> (OutputAudioSinkSession, Stream, Arc<RecordingSession>) {
+        let (producer, consumer) = RingBuffer::new(200);
+        let format = Arc::new(StreamFormat {
+            channels: AtomicU8::new(1),
+            rate: AtomicU32::new(1000),
+            overruns: AtomicU64::new(0),
+        });
+        let output = Arc::new(RecordingSession::default());
+
+        let session = OutputAudioSinkSession {
+            producer: Mutex::new(producer),
+            format: format.clone(),
+            output: output.clone(),
+        };
+        let stream = Stream {
+            consumer,
+            format,
+            output: output.clone(),
+            clock: None,
+            underruns: 0,
+            starved: false,
+            closing: false,
+        };
+
+        (session, stream, output)
+    }
+
+    fn samples(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|x| x.to_ne_bytes()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_output() -> Result<()> {
+        let (session, mut stream, output) = stream();
+        let now = Instant::now();
+
+        // waits for prebuffer of 50 frames
+        session.write(&samples(&[1000; 40]), 1, 1000, AudioFormat::S16NE)?;
+        assert!(stream.play(now));
+        assert!(output.samples.lock().unwrap().is_empty());
+
+        // prebuffer is written ahead, then audio as it's due
+        session.write(&samples(&[1000; 40]), 1, 1000, AudioFormat::S16NE)?;
+        assert!(stream.play(now));
+        assert_eq!(output.samples.lock().unwrap().len(), 50);
+        assert!(stream.play(now + Duration::from_millis(20)));
+        assert_eq!(output.samples.lock().unwrap().len(), 70);
+
+        // underrun is filled with silence on every tick until audio is back
+        assert!(stream.play(now + Duration::from_millis(40)));
+        assert_eq!(*output.samples.lock().unwrap(), [vec![1000; 80], vec![0; 10]].concat());
+        assert!(stream.play(now + Duration::from_millis(50)));
+        assert!(stream.play(now + Duration::from_millis(60)));
+        assert_eq!(*output.samples.lock().unwrap(), [vec![1000; 80], vec![0; 30]].concat());
+        assert_eq!(stream.underruns, 1);
+
+        // on the same clock, without waiting for prebuffer
+        session.write(&samples(&[2000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        assert!(stream.play(now + Duration::from_millis(70)));
+        assert_eq!(output.samples.lock().unwrap()[110..], [2000; 10]);
+
+        // rest is played out after the session ended
+        session.write(&samples(&[2000; 10]), 1, 1000, AudioFormat::S16NE)?;
+        drop(session);
+        assert!(!stream.play(now + Duration::from_millis(90)));
+        assert_eq!(output.samples.lock().unwrap().len(), 130);
+        assert_eq!(stream.underruns, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overrun() -> Result<()> {
+        let (session, mut stream, output) = stream();
+
+        session.write(&samples(&[1000; 150]), 1, 1000, AudioFormat::S16NE)?;
+        session.write(&samples(&[2000; 100]), 1, 1000, AudioFormat::S16NE)?;
+        assert_eq!(session.format.overruns.load(Ordering::Relaxed), 1);
+
+        drop(session);
+        stream.closing = true;
+        assert!(stream.play(Instant::now()));
+        assert!(!stream.play(Instant::now() + Duration::from_secs(1)));
+        assert_eq!(*output.samples.lock().unwrap(), [vec![1000; 150], vec![2000; 50]].concat());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use super::{AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::Result;
+
+// keeps every sample written by its sessions, for tests
+#[derive(Default)]
+pub(crate) struct RecordingSink {
+    pub samples: Arc<Mutex<Vec<i16>>>,
+    pub starts: AtomicUsize,
+}
+
+#[derive(Default)]
+pub(crate) struct RecordingSession {
+    pub samples: Arc<Mutex<Vec<i16>>>,
+}
+
+impl AudioSink for RecordingSink {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        self.starts.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Arc::new(RecordingSession {
+            samples: self.samples.clone(),
+        }))
+    }
+}
+
+impl AudioSinkSession for RecordingSession {
+    fn write(&self, payload: &[u8], _: u8, _: u32, _: AudioFormat) -> Result<()> {
+        let mut samples = self.samples.lock().unwrap();
+        samples.extend(payload.chunks_exact(2).map(|x| i16::from_ne_bytes([x[0], x[1]])));
+
+        Ok(())
+    }
+
+    fn set_volume(&self, _: f32) {}
+}
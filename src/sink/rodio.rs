@@ -1,30 +1,51 @@
-use std::rc::Rc;
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
 
-use anyhow::Result;
 use cfg_if::cfg_if;
 use rodio::{buffer::SamplesBuffer, OutputStream, OutputStreamHandle, Sink};
 
 use super::{AudioFormat, AudioSink, AudioSinkSession};
-use crate::util::convert_vec;
+use crate::{
+    error::{Error, Result},
+    util::convert_vec,
+};
 
 pub struct RodioAudioSink {
-    _stream: OutputStream,
     stream_handle: OutputStreamHandle,
+    // dropping it ends the thread keeping the output stream open
+    _stop: mpsc::SyncSender<()>,
 }
 
 impl RodioAudioSink {
-    pub fn new() -> Self {
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+    pub fn new() -> Result<Self> {
+        // output stream isn't Send, so it lives on its own thread and sessions share its handle
+        let (handle_sender, handle_receiver) = mpsc::sync_channel(1);
+        let (stop, stopped) = mpsc::sync_channel::<()>(0);
+        thread::Builder::new()
+            .name("rodio".into())
+            .spawn(move || match OutputStream::try_default() {
+                Ok((_stream, stream_handle)) => {
+                    let _ = handle_sender.send(Ok(stream_handle));
+                    let _ = stopped.recv();
+                }
+                Err(err) => {
+                    let _ = handle_sender.send(Err(Error::Sink(err.to_string())));
+                }
+            })?;
 
-        Self { _stream, stream_handle }
+        let stream_handle = handle_receiver.recv().map_err(|_| Error::Sink("Audio output thread exited".into()))??;
+
+        Ok(Self { stream_handle, _stop: stop })
     }
 }
 
 impl AudioSink for RodioAudioSink {
-    fn start(&self) -> Result<Rc<dyn AudioSinkSession>> {
-        let sink = Sink::try_new(&self.stream_handle)?;
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        let sink = Sink::try_new(&self.stream_handle).map_err(|err| Error::Sink(err.to_string()))?;
 
-        Ok(Rc::new(RodioAudioSinkSession::new(sink)?))
+        Ok(Arc::new(RodioAudioSinkSession::new(sink)?))
     }
 }
 
@@ -63,11 +84,7 @@ impl AudioSinkSession for RodioAudioSinkSession {
         Ok(())
     }
 
-    fn set_volume(&self, volume: f32) {
-        // airplay volume: -30.0 ~ 0.0, -144: mute, -20: default
-        // it's in decibel, but i'm lazy to convert it correctly into linear scale..
-        let volume = if volume == -144.0 { 0.0 } else { 1.0 + (volume / 30.0) };
-
-        self.sink.set_volume(volume);
+    fn set_volume(&self, gain: f32) {
+        self.sink.set_volume(gain);
     }
 }
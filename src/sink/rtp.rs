@@ -0,0 +1,242 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::{Error, Result};
+
+// fits in a single ethernet frame with ip, udp and rtp headers
+const MAX_PAYLOAD_SIZE: usize = 1440;
+
+const RTP_HEADER_SIZE: usize = 12;
+const RTP_DYNAMIC_PAYLOAD_TYPE: u8 = 96;
+
+// outgoing interface of multicast, ipv4 takes the interface's address and ipv6 its index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum MulticastInterface {
+    Address(Ipv4Addr),
+    Index(u32),
+}
+
+// Sends L16 audio over RTP to a multicast (or unicast) destination.
+pub struct RtpAudioSink {
+    socket: Arc<UdpSocket>,
+    destination: SocketAddr,
+}
+
+impl RtpAudioSink {
+    pub fn new(destination: SocketAddr) -> Result<Self> {
+        Self::with_multicast(destination, None, None)
+    }
+
+    // ttl defaults to 1, so multicast doesn't cross routers unless it's raised
+    pub fn with_multicast(destination: SocketAddr, ttl: Option<u32>, interface: Option<MulticastInterface>) -> Result<Self> {
+        let socket = match destination {
+            SocketAddr::V4(_) => {
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+                if let Some(ttl) = ttl {
+                    socket.set_multicast_ttl_v4(ttl)?;
+                }
+                match interface {
+                    Some(MulticastInterface::Address(addr)) => socket.set_multicast_if_v4(&addr)?,
+                    Some(MulticastInterface::Index(_)) => return Err(Error::Sink("Ipv4 multicast interface has to be an address".into())),
+                    None => {}
+                }
+                socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into())?;
+                socket
+            }
+            SocketAddr::V6(_) => {
+                let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+                if let Some(ttl) = ttl {
+                    socket.set_multicast_hops_v6(ttl)?;
+                }
+                match interface {
+                    Some(MulticastInterface::Index(index)) => socket.set_multicast_if_v6(index)?,
+                    Some(MulticastInterface::Address(_)) => return Err(Error::Sink("Ipv6 multicast interface has to be an index".into())),
+                    None => {}
+                }
+                socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0).into())?;
+                socket
+            }
+        };
+        // packets are dropped rather than stalling the output thread when send buffer is full
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: Arc::new(socket.into()),
+            destination,
+        })
+    }
+}
+
+impl AudioSink for RtpAudioSink {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        Ok(Arc::new(RtpAudioSinkSession::new(self.socket.clone(), self.destination)))
+    }
+}
+
+struct RtpState {
+    sequence: u16,
+    timestamp: u32,
+    marker: bool,
+}
+
+pub struct RtpAudioSinkSession {
+    socket: Arc<UdpSocket>,
+    destination: SocketAddr,
+    ssrc: u32,
+    state: Mutex<RtpState>,
+}
+
+impl RtpAudioSinkSession {
+    fn new(socket: Arc<UdpSocket>, destination: SocketAddr) -> Self {
+        // no need for strong randomness, we only have to differ from other sessions
+        let ssrc = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or_default();
+
+        Self {
+            socket,
+            destination,
+            ssrc,
+            state: Mutex::new(RtpState {
+                sequence: 0,
+                timestamp: 0,
+                marker: true,
+            }),
+        }
+    }
+
+    fn payload_type(channels: u8, rate: u32) -> u8 {
+        // static payload types from rfc3551, anything else should be described by sdp on the receiving side
+        match (channels, rate) {
+            (2, 44100) => 10,
+            (1, 44100) => 11,
+            _ => RTP_DYNAMIC_PAYLOAD_TYPE,
+        }
+    }
+}
+
+impl AudioSinkSession for RtpAudioSinkSession {
+    fn write(&self, payload: &[u8], channels: u8, rate: u32, format: AudioFormat) -> Result<()> {
+        let frame_size = channels as usize * 2;
+        let payload_type = Self::payload_type(channels, rate);
+
+        let mut state = self.state.lock().unwrap();
+        for chunk in payload.chunks(MAX_PAYLOAD_SIZE / frame_size * frame_size) {
+            let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + chunk.len());
+
+            packet.push(0x80); // version 2, no padding, no extension, no csrc
+            packet.push(if state.marker { 0x80 } else { 0x00 } | payload_type);
+            packet.extend_from_slice(&state.sequence.to_be_bytes());
+            packet.extend_from_slice(&state.timestamp.to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+
+            // L16 is always big endian on the wire
+            match format {
+                AudioFormat::S16BE => packet.extend_from_slice(chunk),
+                AudioFormat::S16NE => {
+                    for sample in chunk.chunks_exact(2) {
+                        packet.extend_from_slice(&i16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes());
+                    }
+                }
+            }
+
+            match self.socket.send_to(&packet, self.destination) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => debug!("Dropping rtp packet, send buffer is full"),
+                result => {
+                    result?;
+                }
+            }
+
+            state.sequence = state.sequence.wrapping_add(1);
+            state.timestamp = state.timestamp.wrapping_add((chunk.len() / frame_size) as u32);
+            state.marker = false;
+        }
+
+        Ok(())
+    }
+
+    fn set_volume(&self, gain: f32) {
+        trace!("RtpAudioSink::set_volume {:?}", gain);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_rtp_packet() -> Result<()> {
+        let receiver = UdpSocket::bind("127.0.0.1:0")?;
+        let sink = RtpAudioSink::new(receiver.local_addr()?)?;
+        let session = sink.start()?;
+
+        let payload = [1i16, -2, 3, -4].iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<_>>();
+        session.write(&payload, 2, 44100, AudioFormat::S16NE)?;
+        session.write(&payload, 2, 44100, AudioFormat::S16NE)?;
+
+        let mut buf = [0; 1500];
+
+        let length = receiver.recv(&mut buf)?;
+        assert_eq!(length, RTP_HEADER_SIZE + 8);
+        assert_eq!(buf[0], 0x80);
+        assert_eq!(buf[1], 0x80 | 10);
+        assert_eq!(&buf[2..8], &[0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[RTP_HEADER_SIZE..length], &[0x00, 0x01, 0xff, 0xfe, 0x00, 0x03, 0xff, 0xfc]);
+
+        let length = receiver.recv(&mut buf)?;
+        assert_eq!(length, RTP_HEADER_SIZE + 8);
+        assert_eq!(buf[1], 10);
+        assert_eq!(&buf[2..8], &[0, 1, 0, 0, 0, 2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multicast_options() -> Result<()> {
+        let sink = RtpAudioSink::with_multicast(
+            "239.255.0.1:5004".parse()?,
+            Some(16),
+            Some(MulticastInterface::Address(Ipv4Addr::LOCALHOST)),
+        )?;
+        let socket = socket2::SockRef::from(&*sink.socket);
+        assert_eq!(socket.multicast_ttl_v4()?, 16);
+        assert_eq!(socket.multicast_if_v4()?, Ipv4Addr::LOCALHOST);
+
+        assert_eq!(RtpAudioSink::new("239.255.0.1:5004".parse()?)?.socket.multicast_ttl_v4()?, 1);
+        assert!(RtpAudioSink::with_multicast("239.255.0.1:5004".parse()?, None, Some(MulticastInterface::Index(1))).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rtp_split() -> Result<()> {
+        let receiver = UdpSocket::bind("127.0.0.1:0")?;
+        let sink = RtpAudioSink::new(receiver.local_addr()?)?;
+        let session = sink.start()?;
+
+        session.write(&vec![0; 4096], 2, 48000, AudioFormat::S16BE)?;
+
+        let mut buf = [0; 1500];
+        let mut total = 0;
+        while total < 4096 {
+            let length = receiver.recv(&mut buf)?;
+            assert_eq!(buf[1] & 0x7f, RTP_DYNAMIC_PAYLOAD_TYPE);
+            assert!(length - RTP_HEADER_SIZE <= MAX_PAYLOAD_SIZE);
+            assert_eq!((length - RTP_HEADER_SIZE) % 4, 0);
+
+            total += length - RTP_HEADER_SIZE;
+        }
+        assert_eq!(total, 4096);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use bytes::Bytes;
+use log::{debug, trace, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+
+use super::{AudioFormat, AudioSink, AudioSinkSession};
+use crate::error::Result;
+
+// about a second of 44100hz stereo audio in 352 frame packets
+const BUFFERED_PACKETS: usize = 128;
+
+// Serves raw s16le pcm to every connected client, e.g. `nc <host> <port> | aplay -f cd`
+pub struct TcpAudioSink {
+    local_addr: SocketAddr,
+    sender: broadcast::Sender<Bytes>,
+    accept_join_handle: JoinHandle<()>,
+}
+
+impl TcpAudioSink {
+    pub fn new(addr: SocketAddr) -> Result<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+
+        let local_addr = listener.local_addr()?;
+        let (sender, _) = broadcast::channel(BUFFERED_PACKETS);
+
+        let accept_join_handle = tokio::spawn(Self::accept_loop(listener, sender.clone()));
+
+        let sink = Self {
+            local_addr,
+            sender,
+            accept_join_handle,
+        };
+        debug!("TcpAudioSink listening on {}", sink.local_addr());
+
+        Ok(sink)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    async fn accept_loop(listener: TcpListener, sender: broadcast::Sender<Bytes>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("TcpAudioSink client connected {}", addr);
+
+                    tokio::spawn(Self::client_loop(stream, addr, sender.subscribe()));
+                }
+                Err(err) => warn!("TcpAudioSink accept failed {:?}", err),
+            }
+        }
+    }
+
+    async fn client_loop(mut stream: TcpStream, addr: SocketAddr, mut receiver: broadcast::Receiver<Bytes>) {
+        loop {
+            match receiver.recv().await {
+                Ok(data) => {
+                    if let Err(err) = stream.write_all(&data).await {
+                        debug!("TcpAudioSink client {} disconnected {:?}", addr, err);
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => warn!("TcpAudioSink client {} is too slow, skipped {} packets", addr, count),
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+impl Drop for TcpAudioSink {
+    fn drop(&mut self) {
+        self.accept_join_handle.abort();
+    }
+}
+
+impl AudioSink for TcpAudioSink {
+    fn start(&self) -> Result<Arc<dyn AudioSinkSession>> {
+        Ok(Arc::new(TcpAudioSinkSession { sender: self.sender.clone() }))
+    }
+}
+
+pub struct TcpAudioSinkSession {
+    sender: broadcast::Sender<Bytes>,
+}
+
+impl AudioSinkSession for TcpAudioSinkSession {
+    fn write(&self, payload: &[u8], _: u8, _: u32, format: AudioFormat) -> Result<()> {
+        let data = payload
+            .chunks_exact(2)
+            .map(|x| match format {
+                AudioFormat::S16BE => i16::from_be_bytes([x[0], x[1]]),
+                AudioFormat::S16NE => i16::from_ne_bytes([x[0], x[1]]),
+            })
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        // it's fine to have no listeners
+        let _ = self.sender.send(data.into());
+
+        Ok(())
+    }
+
+    fn set_volume(&self, gain: f32) {
+        trace!("TcpAudioSink::set_volume {:?}", gain);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use std::time::Duration;
+    use tokio::{io::AsyncReadExt, time::timeout};
+
+    #[tokio::test]
+    async fn test_tcp_clients() -> Result<()> {
+        let sink = TcpAudioSink::new("127.0.0.1:0".parse()?)?;
+        let session = sink.start()?;
+
+        let mut clients = vec![TcpStream::connect(sink.local_addr()).await?, TcpStream::connect(sink.local_addr()).await?];
+
+        let payload = [0x00u8, 0x01, 0xff, 0xfe];
+        for client in &mut clients {
+            // clients may not be subscribed yet, so keep writing until data arrives
+            let mut buf = [0; 4];
+            loop {
+                session.write(&payload, 2, 44100, AudioFormat::S16BE)?;
+
+                if let Ok(result) = timeout(Duration::from_millis(50), client.read_exact(&mut buf)).await {
+                    result?;
+                    break;
+                }
+            }
+            assert_eq!(buf, [0x01, 0x00, 0xfe, 0xff]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use serde::Deserialize;
+
+// airplay volume is in dB, -30.0 ~ 0.0, with -144.0 for mute
+const AIRPLAY_MIN_VOLUME: f32 = -30.0;
+const AIRPLAY_MAX_VOLUME: f32 = 0.0;
+const AIRPLAY_MUTE: f32 = -144.0;
+
+// maps airplay volume to linear gain, applied to audio before it reaches the sink
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeCurve {
+    // output level at sender's max volume
+    pub max_db: f32,
+    // output level at sender's min volume, below that is mute
+    pub min_db: f32,
+    // always play at max_db, e.g. when volume is controlled by an amplifier
+    pub ignore_sender: bool,
+}
+
+impl Default for VolumeCurve {
+    fn default() -> Self {
+        Self {
+            max_db: AIRPLAY_MAX_VOLUME,
+            min_db: AIRPLAY_MIN_VOLUME,
+            ignore_sender: false,
+        }
+    }
+}
+
+impl VolumeCurve {
+    pub fn gain(&self, volume: f32) -> f32 {
+        if self.ignore_sender {
+            return db_to_gain(self.max_db);
+        }
+        if volume <= AIRPLAY_MUTE {
+            return 0.0;
+        }
+
+        // sender's range is stretched linearly in dB, so each step is the same loudness change
+        let position = (volume.clamp(AIRPLAY_MIN_VOLUME, AIRPLAY_MAX_VOLUME) - AIRPLAY_MIN_VOLUME) / (AIRPLAY_MAX_VOLUME - AIRPLAY_MIN_VOLUME);
+
+        db_to_gain(self.min_db + position * (self.max_db - self.min_db))
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub(crate) fn apply_gain(gain: f32, sample: i16) -> i16 {
+    (sample as f32 * gain).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+
+    fn assert_near(x: f32, y: f32) {
+        assert!((x - y).abs() < 0.001, "{x} != {y}");
+    }
+
+    #[tokio::test]
+    async fn test_volume_curve() -> Result<()> {
+        let curve = VolumeCurve::default();
+
+        assert_near(curve.gain(0.0), 1.0);
+        assert_near(curve.gain(-6.0), 0.501);
+        assert_near(curve.gain(-30.0), 0.0316);
+        assert_near(curve.gain(-144.0), 0.0);
+
+        let curve = VolumeCurve {
+            max_db: -10.0,
+            min_db: -50.0,
+            ignore_sender: false,
+        };
+        assert_near(curve.gain(0.0), 0.316);
+        assert_near(curve.gain(-15.0), 0.0316);
+
+        let curve = VolumeCurve {
+            ignore_sender: true,
+            ..curve
+        };
+        assert_near(curve.gain(-144.0), 0.316);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gain() -> Result<()> {
+        assert_eq!(apply_gain(1.0, 1001), 1001);
+        assert_eq!(apply_gain(0.5, 1001), 501);
+        assert_eq!(apply_gain(2.0, i16::MIN), i16::MIN);
+
+        Ok(())
+    }
+}